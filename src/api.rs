@@ -0,0 +1,330 @@
+// MIT License
+// Copyright (c) 2024 Graham King
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::thread;
+use std::time::Duration;
+
+use reqwest::header::HeaderMap;
+
+// Don't let a confused or hostile server park us for hours
+const MAX_PROVIDER_WAIT: Duration = Duration::from_secs(15 * 60);
+
+/// How long to wait and how hard to try. `[http]` in the config file.
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Retries after the first attempt, for rate limits, server errors and timeouts
+    pub max_retries: u32,
+    /// Wait before the first retry. Doubles each time.
+    pub initial_backoff_ms: u64,
+    /// Never wait longer than this between tries, unless the provider tells us to
+    pub max_backoff_secs: u64,
+    /// Whole request, including reading the response. Long summaries take a while.
+    pub timeout_secs: u64,
+    pub connect_timeout_secs: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            max_retries: 6,
+            initial_backoff_ms: 1000,
+            max_backoff_secs: 60,
+            timeout_secs: 300,
+            connect_timeout_secs: 10,
+        }
+    }
+}
+
+/// An HTTP client for model APIs. It keeps connections open between requests,
+/// retries with exponential backoff and jitter, follows the provider's
+/// `Retry-After` and rate limit headers, and puts the provider's error message
+/// in the error.
+/// Cheap to clone and safe to share between threads.
+#[derive(Clone)]
+pub struct Client {
+    inner: reqwest::blocking::Client,
+    settings: Settings,
+}
+
+impl Client {
+    pub fn new(settings: &Settings) -> anyhow::Result<Client> {
+        let inner = reqwest::blocking::Client::builder()
+            .timeout(Duration::from_secs(settings.timeout_secs))
+            .connect_timeout(Duration::from_secs(settings.connect_timeout_secs))
+            .build()?;
+        Ok(Client {
+            inner,
+            settings: settings.clone(),
+        })
+    }
+
+    pub fn post(&self, url: &str) -> reqwest::blocking::RequestBuilder {
+        self.inner.post(url)
+    }
+
+    /// Send the request and decode the JSON response
+    pub fn send_json<T: serde::de::DeserializeOwned>(
+        &self,
+        req: reqwest::blocking::RequestBuilder,
+    ) -> anyhow::Result<T> {
+        let mut attempt = 0;
+        loop {
+            let Some(this_try) = req.try_clone() else {
+                // Only streaming bodies can't be cloned, and we always send JSON
+                return Err(anyhow::anyhow!("Request can't be retried"));
+            };
+            let (err, wait) = match this_try.send() {
+                Ok(res) if res.status().is_success() => return Ok(res.json()?),
+                Ok(res) => {
+                    let status = res.status();
+                    let wait = provider_wait(res.headers());
+                    let err = anyhow::anyhow!(
+                        "HTTP error {status}: {}",
+                        error_message(&res.text().unwrap_or_default())
+                    );
+                    if !is_retryable(status) {
+                        return Err(err);
+                    }
+                    (err, wait)
+                }
+                Err(err) if err.is_timeout() || err.is_connect() => (err.into(), None),
+                Err(err) => return Err(err.into()),
+            };
+            if attempt >= self.settings.max_retries {
+                return Err(err.context(format!("Gave up after {} tries", attempt + 1)));
+            }
+            let delay = wait.unwrap_or_else(|| self.backoff(attempt));
+            eprintln!(
+                "\n{err}. Retry {} of {} in {:.1}s",
+                attempt + 1,
+                self.settings.max_retries,
+                delay.as_secs_f64()
+            );
+            thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+
+    // Exponential, with up to 50% jitter so parallel workers don't all come back at once
+    fn backoff(&self, attempt: u32) -> Duration {
+        let base = self
+            .settings
+            .initial_backoff_ms
+            .saturating_mul(1 << attempt.min(16));
+        let jitter = RandomState::new().build_hasher().finish() % (base / 2 + 1);
+        Duration::from_millis(base + jitter)
+            .min(Duration::from_secs(self.settings.max_backoff_secs))
+    }
+}
+
+fn is_retryable(status: http::StatusCode) -> bool {
+    status == http::StatusCode::REQUEST_TIMEOUT
+        || status == http::StatusCode::TOO_MANY_REQUESTS
+        || status.is_server_error()
+}
+
+// How long the provider asked us to wait, if it did, up to MAX_PROVIDER_WAIT
+fn provider_wait(headers: &HeaderMap) -> Option<Duration> {
+    asked_wait(headers).map(|wait| wait.min(MAX_PROVIDER_WAIT))
+}
+
+fn asked_wait(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+    if let Some(ms) = header("retry-after-ms").and_then(|v| v.parse::<f64>().ok()) {
+        return secs(ms / 1000.0);
+    }
+    if let Some(v) = header("retry-after") {
+        if let Ok(n) = v.parse::<f64>() {
+            return secs(n);
+        }
+        if let Ok(at) = chrono::DateTime::parse_from_rfc2822(v) {
+            return until(at.to_utc());
+        }
+    }
+
+    // OpenAI: x-ratelimit-reset-requests: 1s, x-ratelimit-reset-tokens: 6m0s
+    // Anthropic: anthropic-ratelimit-requests-reset: 2024-09-01T12:00:30Z
+    // Only wait for the limits we've used up.
+    let mut wait = None;
+    for limit in ["requests", "tokens"] {
+        if header(&format!("x-ratelimit-remaining-{limit}")) == Some("0") {
+            let reset = header(&format!("x-ratelimit-reset-{limit}")).and_then(parse_go_duration);
+            wait = wait.max(reset);
+        }
+    }
+    for limit in ["requests", "tokens", "input-tokens", "output-tokens"] {
+        if header(&format!("anthropic-ratelimit-{limit}-remaining")) == Some("0") {
+            let reset = header(&format!("anthropic-ratelimit-{limit}-reset"))
+                .and_then(|v| chrono::DateTime::parse_from_rfc3339(v).ok())
+                .and_then(|at| until(at.to_utc()));
+            wait = wait.max(reset);
+        }
+    }
+    wait
+}
+
+fn until(at: chrono::DateTime<chrono::Utc>) -> Option<Duration> {
+    (at - chrono::Utc::now()).to_std().ok()
+}
+
+// Durations like "1s", "6m0s", "20ms", "1h2m3.5s"
+fn parse_go_duration(s: &str) -> Option<Duration> {
+    let mut total = 0.0;
+    let mut rest = s.trim();
+    while !rest.is_empty() {
+        let num_len = rest.find(|c: char| !(c.is_ascii_digit() || c == '.'))?;
+        let n: f64 = rest[..num_len].parse().ok()?;
+        rest = &rest[num_len..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        total += n * match &rest[..unit_len] {
+            "h" => 3600.0,
+            "m" => 60.0,
+            "s" => 1.0,
+            "ms" => 0.001,
+            _ => return None,
+        };
+        rest = &rest[unit_len..];
+    }
+    secs(total)
+}
+
+// None for NaN, infinite and negative seconds, which Duration can't hold
+fn secs(s: f64) -> Option<Duration> {
+    Duration::try_from_secs_f64(s).ok()
+}
+
+// OpenAI and Anthropic send {"error": {"message": "..."}}, Ollama sends {"error": "..."}
+fn error_message(body: &str) -> String {
+    if let Ok(v) = serde_json::from_str::<serde_json::Value>(body) {
+        let e = &v["error"];
+        if let Some(msg) = e["message"].as_str().or(e.as_str()) {
+            return match e["type"].as_str() {
+                Some(t) => format!("{t}: {msg}"),
+                None => msg.to_string(),
+            };
+        }
+    }
+    body.chars().take(500).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.insert(*name, value.parse().unwrap());
+        }
+        map
+    }
+
+    #[test]
+    fn go_durations() {
+        let ms = Duration::from_millis;
+        assert_eq!(parse_go_duration("1s"), Some(ms(1000)));
+        assert_eq!(parse_go_duration("6m0s"), Some(ms(360_000)));
+        assert_eq!(parse_go_duration("20ms"), Some(ms(20)));
+        assert_eq!(parse_go_duration(" 1h2m3.5s "), Some(ms(3_723_500)));
+        assert_eq!(parse_go_duration(""), Some(Duration::ZERO));
+        assert_eq!(parse_go_duration("5"), None);
+        assert_eq!(parse_go_duration("5d"), None);
+        assert_eq!(parse_go_duration("s"), None);
+        assert_eq!(parse_go_duration("1.2.3s"), None);
+        // Too big for a Duration
+        assert_eq!(parse_go_duration(&format!("{}h", "9".repeat(400))), None);
+    }
+
+    #[test]
+    fn waits() {
+        let wait = |pairs: &[(&'static str, &str)]| provider_wait(&headers(pairs));
+        assert_eq!(wait(&[]), None);
+        assert_eq!(
+            wait(&[("retry-after-ms", "1500")]),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(wait(&[("retry-after", "2")]), Some(Duration::from_secs(2)));
+        assert_eq!(
+            wait(&[("retry-after", "0.5")]),
+            Some(Duration::from_millis(500))
+        );
+        // A date that has passed
+        assert_eq!(
+            wait(&[("retry-after", "Wed, 21 Oct 2015 07:28:00 GMT")]),
+            None
+        );
+        for bad in ["-1", "NaN", "inf", "-inf", "1e400"] {
+            assert_eq!(wait(&[("retry-after", bad)]), None, "{bad}");
+            assert_eq!(wait(&[("retry-after-ms", bad)]), None, "{bad}");
+        }
+        assert_eq!(wait(&[("retry-after", "1e9")]), Some(MAX_PROVIDER_WAIT));
+
+        // Only the limits that are used up count, and the longest wins
+        assert_eq!(
+            wait(&[
+                ("x-ratelimit-remaining-requests", "0"),
+                ("x-ratelimit-reset-requests", "1s"),
+                ("x-ratelimit-remaining-tokens", "0"),
+                ("x-ratelimit-reset-tokens", "6m0s"),
+            ]),
+            Some(Duration::from_secs(360))
+        );
+        assert_eq!(
+            wait(&[
+                ("x-ratelimit-remaining-requests", "3"),
+                ("x-ratelimit-reset-requests", "1s"),
+            ]),
+            None
+        );
+        let soon = (chrono::Utc::now() + chrono::TimeDelta::seconds(30)).to_rfc3339();
+        let w = wait(&[
+            ("anthropic-ratelimit-tokens-remaining", "0"),
+            ("anthropic-ratelimit-tokens-reset", &soon),
+        ])
+        .unwrap();
+        assert!(w > Duration::from_secs(25) && w <= Duration::from_secs(30));
+        let much_later = (chrono::Utc::now() + chrono::TimeDelta::days(1)).to_rfc3339();
+        assert_eq!(
+            wait(&[
+                ("anthropic-ratelimit-requests-remaining", "0"),
+                ("anthropic-ratelimit-requests-reset", &much_later),
+            ]),
+            Some(MAX_PROVIDER_WAIT)
+        );
+    }
+
+    #[test]
+    fn error_messages() {
+        assert_eq!(
+            error_message(r#"{"error": {"message": "Rate limit reached", "type": "requests"}}"#),
+            "requests: Rate limit reached"
+        );
+        assert_eq!(
+            error_message(
+                r#"{"type": "error", "error": {"type": "overloaded_error", "message": "Überlastet"}}"#
+            ),
+            "overloaded_error: Überlastet"
+        );
+        assert_eq!(
+            error_message(r#"{"error": {"message": "No type"}}"#),
+            "No type"
+        );
+        assert_eq!(
+            error_message(r#"{"error": "model 'llama9' not found"}"#),
+            "model 'llama9' not found"
+        );
+        assert_eq!(
+            error_message("<html>Bad Gateway</html>"),
+            "<html>Bad Gateway</html>"
+        );
+        assert_eq!(error_message(r#"{"detail": "x"}"#), r#"{"detail": "x"}"#);
+        // Long bodies are cut at a character boundary
+        assert_eq!(error_message(&"日".repeat(600)), "日".repeat(500));
+    }
+}
@@ -3,10 +3,11 @@
 
 use std::path;
 
+use crate::chunk;
+use crate::content;
 use crate::front_matter::FrontMatter;
-
-const CHUNK_SIZE: usize = 2000;
-const MIN_CHUNK: usize = 2500;
+use crate::normalize;
+use crate::site::Site;
 
 #[derive(Debug)]
 pub struct Article {
@@ -14,48 +15,50 @@ pub struct Article {
     pub title: String,
     pub url: String,
     pub date: Option<chrono::DateTime<chrono::FixedOffset>>,
+    // Relative to the directory given on the command line
     pub filename: path::PathBuf,
+    pub section: String,
+    pub lang: String,
+    // What Hugo's site.GetPage needs to find this article
+    pub page_ref: String,
     pub is_draft: bool,
+    pub tags: Vec<String>,
+    // All of the front matter
+    pub params: serde_json::Map<String, serde_json::Value>,
     pub chunks: Vec<String>,
 }
 
 impl Article {
-    pub fn parse(filepath: &path::Path, s: &str) -> anyhow::Result<Article> {
-        let (fm, fm_size) = FrontMatter::extract(s)?;
-
-        let header = [fm.title.clone(), fm.date.clone()];
-
-        // Now gather the body into CHUNK_SIZE chunks
-
-        let mut body: String = s
-            .lines()
-            .skip(fm_size + 2) // Add the two dashes lines we must also skip
-            .collect::<Vec<&str>>()
-            .join("\n");
-        let mut chunks = Vec::new();
-        while body.len() > MIN_CHUNK {
-            let mut split_pos = CHUNK_SIZE;
-            while split_pos < body.len() && body.as_bytes()[split_pos] != b' ' {
-                split_pos += 1;
-            }
-            let rest = body.split_off(split_pos);
-            let mut embed_unit = header.join("\n");
-            embed_unit.push_str("\n\n");
-            embed_unit.push_str(&body);
-            chunks.push(embed_unit);
-            body = rest;
-        }
+    pub fn parse(
+        site: &Site,
+        page: &content::Page,
+        s: &str,
+        chunking: &chunk::Settings,
+        normalize: &normalize::Settings,
+    ) -> anyhow::Result<Article> {
+        let (fm, post) = FrontMatter::extract(s)?;
+        let url = site.permalink(page, &fm);
 
         // Add the title and date to each chunk
         // I figure it helps the embedding
-
-        let mut embed_unit = header.join("\n");
-        embed_unit.push_str(&body);
-        chunks.push(embed_unit);
+        let header = [fm.title.clone(), fm.date.clone()].join("\n");
+        let mut chunks: Vec<String> = chunking
+            .split(post.body, normalize)
+            .into_iter()
+            .map(|chunk| format!("{header}\n\n{chunk}"))
+            .collect();
+        if chunks.is_empty() {
+            chunks.push(header);
+        }
 
         let mut article: Article = fm.into();
         article.chunks = chunks;
-        article.filename = filepath.to_path_buf();
+        article.url = url;
+        article.filename = page.rel_path.clone();
+        article.section = site.section(page);
+        article.lang = page.lang.clone();
+        article.page_ref = site.page_ref(page);
+        article.params = post.parse()?;
         Ok(article)
     }
 }
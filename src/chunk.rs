@@ -0,0 +1,263 @@
+// MIT License
+// Copyright (c) 2024 Graham King
+
+use crate::normalize;
+use crate::tokens;
+
+const DEFAULT_MAX_TOKENS: usize = 500;
+const DEFAULT_OVERLAP: usize = 0;
+
+// Between blocks in a chunk, as Markdown separates paragraphs
+const BLOCK_SEP: &str = "\n\n";
+
+/// How to split a post into chunks for embedding. The `[chunk]` section of the
+/// config file, with command line flags taking priority.
+#[derive(clap::Args, serde::Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Largest chunk in tokens, not counting the title and date we add.
+    /// Code blocks and tables bigger than this are kept whole. [default: 500]
+    #[clap(long = "chunk-tokens", value_name = "TOKENS")]
+    pub max_tokens: Option<usize>,
+
+    /// Tokens from the end of each chunk to repeat at the start of the next [default: 0]
+    #[clap(long = "chunk-overlap", value_name = "TOKENS")]
+    pub overlap: Option<usize>,
+}
+
+// A top level piece of the post: paragraph, list, code block, ...
+// A heading is joined to the block after it so it doesn't end a chunk.
+struct Block {
+    text: String,
+    is_heading: bool,
+    // Carries on from the block before, which was too big and got cut
+    is_continued: bool,
+}
+
+impl Settings {
+    /// Fill in anything not set here from `other`
+    pub fn or(self, other: &Settings) -> Settings {
+        Settings {
+            max_tokens: self.max_tokens.or(other.max_tokens),
+            overlap: self.overlap.or(other.overlap),
+        }
+    }
+
+    pub fn max_tokens(&self) -> usize {
+        self.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS).max(1)
+    }
+
+    /// Never more than half a chunk, or the chunks would barely move forward
+    pub fn overlap(&self) -> usize {
+        self.overlap
+            .unwrap_or(DEFAULT_OVERLAP)
+            .min(self.max_tokens() / 2)
+    }
+
+    /// Split the Markdown `body` into plain text chunks of at most `max_tokens`.
+    /// Chunks end between blocks where possible, and a heading starts a new
+    /// chunk unless the current one is less than half full.
+    pub fn split(&self, body: &str, normalize: &normalize::Settings) -> Vec<String> {
+        let max = self.max_tokens();
+        let mut chunks = Vec::new();
+        let mut current = String::new();
+        let mut current_tokens = 0;
+        // How much of `current` is repeated from the chunk before
+        let mut overlap_tokens = 0;
+        for block in blocks(body, max, normalize) {
+            let sep = if block.is_continued { " " } else { BLOCK_SEP };
+            let is_full = tokens::count(&join(&current, &block.text, sep)) > max
+                || (block.is_heading && current_tokens >= max / 2);
+            if current_tokens > overlap_tokens && is_full {
+                let tail = tail(&current, self.overlap()).to_string();
+                chunks.push(std::mem::replace(&mut current, tail));
+                overlap_tokens = tokens::count(&current);
+                if tokens::count(&join(&current, &block.text, sep)) > max {
+                    // No room to repeat anything
+                    current.clear();
+                    overlap_tokens = 0;
+                }
+            }
+            current = join(&current, &block.text, sep);
+            current_tokens = tokens::count(&current);
+        }
+        if current_tokens > overlap_tokens {
+            chunks.push(current);
+        }
+        chunks
+    }
+}
+
+fn join(chunk: &str, block: &str, sep: &str) -> String {
+    if chunk.is_empty() {
+        block.to_string()
+    } else {
+        format!("{chunk}{sep}{block}")
+    }
+}
+
+// The top level blocks of `body` as text, with any bigger than `max` tokens
+// cut up, except those that only make sense whole
+fn blocks(body: &str, max: usize, normalize: &normalize::Settings) -> Vec<Block> {
+    let mut out: Vec<Block> = Vec::new();
+    // The last block is a heading with nothing after it yet
+    let mut is_open_heading = false;
+    for block in normalize.blocks(body) {
+        let text = block.text.as_str();
+        let was_open_heading = std::mem::replace(&mut is_open_heading, block.is_heading);
+        let pieces = if block.is_whole || tokens::count(text) <= max {
+            vec![text]
+        } else {
+            cut(text, max)
+        };
+        for (idx, piece) in pieces.into_iter().enumerate() {
+            let is_first = idx == 0;
+            if let Some(prev) = out
+                .last_mut()
+                .filter(|_| is_first && was_open_heading && !block.is_heading)
+            {
+                let joined = join(&prev.text, piece, BLOCK_SEP);
+                if tokens::count(&joined) <= max {
+                    prev.text = joined;
+                    continue;
+                }
+            }
+            out.push(Block {
+                text: piece.to_string(),
+                is_heading: block.is_heading,
+                is_continued: !is_first,
+            });
+        }
+    }
+    out
+}
+
+// Cut `text` into pieces of at most `max` tokens, between words. A "word" that
+// is too long on its own, such as a line of Chinese, is cut between characters.
+fn cut(text: &str, max: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    let mut tokens = 0;
+    let mut pos = 0;
+    for word in text.split_inclusive(char::is_whitespace) {
+        let word_tokens = tokens::count(word);
+        let units: Vec<(usize, usize)> = if word_tokens <= max {
+            vec![(word.len(), word_tokens)]
+        } else {
+            word.chars()
+                .map(|c| (c.len_utf8(), tokens::count(c.encode_utf8(&mut [0; 4]))))
+                .collect()
+        };
+        for (len, unit_tokens) in units {
+            if tokens + unit_tokens > max && pos > start {
+                pieces.push(text[start..pos].trim_end());
+                start = pos;
+                tokens = 0;
+            }
+            tokens += unit_tokens;
+            pos += len;
+        }
+    }
+    if start < text.len() {
+        pieces.push(text[start..].trim_end());
+    }
+    pieces.retain(|p| !p.is_empty());
+    pieces
+}
+
+// The end of `text`, starting at a word, of about `want` tokens
+fn tail(text: &str, want: usize) -> &str {
+    if want == 0 {
+        return "";
+    }
+    let mut start = text.len();
+    for (idx, c) in text.char_indices().rev() {
+        if !c.is_whitespace() {
+            continue;
+        }
+        start = idx + c.len_utf8();
+        if tokens::count(&text[start..]) >= want {
+            break;
+        }
+    }
+    text[start..].trim_start()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(max_tokens: usize, overlap: usize, body: &str) -> Vec<String> {
+        let settings = Settings {
+            max_tokens: Some(max_tokens),
+            overlap: Some(overlap),
+        };
+        settings.split(body, &normalize::Settings::default())
+    }
+
+    #[test]
+    fn short_post_is_one_chunk() {
+        let body = "Grüße aus Köln! ☕\n\nZweiter Absatz, mit Umlauten: äöü ß.";
+        assert_eq!(split(100, 0, body), vec![body.to_string()]);
+    }
+
+    #[test]
+    fn empty_post_has_no_chunks() {
+        assert!(split(100, 0, "").is_empty());
+        assert!(split(100, 0, "\n\n  \n").is_empty());
+    }
+
+    #[test]
+    fn long_paragraphs_split_on_char_boundaries() {
+        // No spaces at all, and every character is several bytes
+        let body =
+            "日本語の文章はスペースで単語を区切らない。".repeat(40) + "\n\n" + &"🦀 ".repeat(300);
+        let chunks = split(50, 0, &body);
+        assert!(chunks.len() > 2);
+        for c in chunks.iter() {
+            assert!(tokens::count(c) <= 50, "{} tokens: {c}", tokens::count(c));
+        }
+        let joined: String = chunks.concat();
+        let strip = |s: &str| s.chars().filter(|c| !c.is_whitespace()).collect::<String>();
+        assert_eq!(strip(&joined), strip(&body));
+    }
+
+    #[test]
+    fn code_blocks_are_kept_whole() {
+        let code = "let café = \"naïve\";\n".repeat(50);
+        let body = format!("Avant le code.\n\n```rust\n{code}```\n\nAprès le code.");
+        let chunks = split(40, 0, &body);
+        assert!(chunks.iter().any(|c| c == code.trim()), "{chunks:?}");
+        assert!(chunks
+            .iter()
+            .all(|c| !c.contains("café") || c == code.trim()));
+    }
+
+    #[test]
+    fn headings_start_a_chunk() {
+        let para = "Ελληνικό κείμενο για δοκιμή. ".repeat(4);
+        let para = para.trim_end();
+        let body = format!("# Πρώτο\n\n{para}\n\n## Δεύτερο\n\n{para}");
+        let whole = format!("Πρώτο\n\n{para}\n\nΔεύτερο\n\n{para}");
+        let chunks = split(tokens::count(&whole) - 1, 0, &body);
+        assert_eq!(chunks.len(), 2, "{chunks:?}");
+        assert!(chunks[0].starts_with("Πρώτο"));
+        assert!(chunks[1].starts_with("Δεύτερο"));
+    }
+
+    #[test]
+    fn overlap_repeats_the_end_of_the_previous_chunk() {
+        let body = (1..=60)
+            .map(|n| format!("Абзац номер {n}, ёж."))
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let chunks = split(60, 10, &body);
+        assert!(chunks.len() > 1);
+        for pair in chunks.windows(2) {
+            let repeated = tail(&pair[0], 10);
+            assert!(!repeated.is_empty());
+            assert!(pair[1].starts_with(repeated), "{pair:?}");
+        }
+        assert!(chunks.last().unwrap().ends_with("Абзац номер 60, ёж."));
+    }
+}
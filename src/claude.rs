@@ -1,15 +1,20 @@
 // MIT License
 // Copyright (c) 2024 Graham King
 
+use crate::api;
+
 pub const CHAT_MODEL_BIG: &str = "claude-3-5-sonnet-20240620";
 pub const CHAT_MODEL_SMALL: &str = "claude-3-haiku-20240307";
 
+// Anthropic make us choose
+const DEFAULT_MAX_TOKENS: usize = 1024;
+
 #[derive(Debug, serde::Serialize)]
-struct ChatRequest {
+struct ChatRequest<'a> {
     model: &'static str,
     max_tokens: usize,
     #[serde(skip_serializing_if = "str::is_empty")]
-    system: &'static str,
+    system: &'a str,
     messages: Vec<ChatMessage>,
 }
 
@@ -28,36 +33,47 @@ struct ChatResponseText {
     text: String,
 }
 
-pub fn message(model: &'static str, s: &str, prompts: super::Prompts) -> anyhow::Result<String> {
+pub fn message(
+    client: &api::Client,
+    model: &'static str,
+    s: &str,
+    prompts: super::Prompts,
+    // Longest answer, in tokens
+    max_tokens: Option<usize>,
+) -> anyhow::Result<String> {
     let Ok(api_key) = std::env::var("ANTHROPIC_API_KEY") else {
         return Err(anyhow::anyhow!(
             "Set variable ANTHROPIC_API_KEY to your key"
         ));
     };
+    // Claude wants user and assistant turns to alternate, which examples do
+    let mut messages = Vec::with_capacity(prompts.examples.len() * 2 + 1);
+    for example in prompts.examples {
+        messages.push(ChatMessage {
+            role: "user".to_string(),
+            content: format!("{}\n\n{}", example.user, example.text),
+        });
+        messages.push(ChatMessage {
+            role: "assistant".to_string(),
+            content: example.answer.clone(),
+        });
+    }
+    messages.push(ChatMessage {
+        role: "user".to_string(),
+        content: format!("{}\n\n{s}", prompts.user),
+    });
     let req = ChatRequest {
         model,
-        max_tokens: 1024,
+        max_tokens: max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
         system: prompts.system,
-        messages: vec![ChatMessage {
-            role: "user".to_string(),
-            content: format!("{}\n\n{s}", prompts.user),
-        }],
+        messages,
     };
-    let client = reqwest::blocking::Client::new();
-    let res = client
+    let req_builder = client
         .post("https://api.anthropic.com/v1/messages")
         .header("x-api-key", api_key)
         .header("anthropic-version", "2023-06-01")
-        .json(&req)
-        .send()?;
-    if res.status() != http::StatusCode::OK {
-        return Err(anyhow::anyhow!(
-            "HTTP error {} {:?}",
-            res.status(),
-            res.text()
-        ));
-    }
-    let mut out: ChatResponse = res.json()?;
+        .json(&req);
+    let mut out: ChatResponse = client.send_json(req_builder)?;
     let Some(c0) = out.content.pop() else {
         return Err(anyhow::anyhow!("No content in response: {out:?}"));
     };
@@ -0,0 +1,55 @@
+// MIT License
+// Copyright (c) 2024 Graham King
+
+use std::collections::HashMap;
+use std::fs;
+use std::path;
+
+use anyhow::Context;
+
+use crate::api;
+use crate::chunk;
+use crate::embedder;
+use crate::field;
+use crate::generator::Generator;
+use crate::normalize;
+use crate::similar::related;
+
+pub const CONFIG_NAME: &str = "hugo-ai.toml";
+
+/// Settings from hugo-ai.toml. Everything is optional.
+#[derive(serde::Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub embed: embedder::Settings,
+    pub chunk: chunk::Settings,
+    pub normalize: normalize::Settings,
+    pub long_posts: field::LongPosts,
+    pub http: api::Settings,
+    pub related: related::Settings,
+    pub generators: HashMap<String, Generator>,
+}
+
+impl Config {
+    /// Read the config file at `path` if given, otherwise hugo-ai.toml in the
+    /// current directory, otherwise hugo-ai.toml in `cfg_dir`.
+    /// No config file is fine, you get the defaults.
+    pub fn load(path: Option<&str>, cfg_dir: &str) -> anyhow::Result<Config> {
+        let candidate = match path {
+            Some(p) => path::PathBuf::from(p),
+            None => [
+                path::PathBuf::from(CONFIG_NAME),
+                path::Path::new(cfg_dir).join(CONFIG_NAME),
+            ]
+            .into_iter()
+            .find(|p| p.is_file())
+            .unwrap_or_default(),
+        };
+        if candidate.as_os_str().is_empty() {
+            return Ok(Config::default());
+        }
+        let s = fs::read_to_string(&candidate)
+            .with_context(|| format!("Reading config {}", candidate.display()))?;
+        toml::from_str(&s).with_context(|| format!("Parsing config {}", candidate.display()))
+    }
+}
@@ -0,0 +1,262 @@
+// MIT License
+// Copyright (c) 2024 Graham King
+
+use std::fs;
+use std::path;
+
+use anyhow::Context;
+
+const MARKDOWN_EXTENSIONS: [&str; 2] = ["md", "markdown"];
+
+/// Which posts to work on, by path relative to the content directory
+#[derive(clap::Args, Clone, Default)]
+pub struct Filter {
+    /// Only process posts whose path matches this glob, e.g. "2023/**". Repeatable.
+    #[clap(long, visible_alias = "only", value_name = "GLOB")]
+    pub include: Vec<String>,
+
+    /// Skip posts whose path matches this glob, e.g. "drafts/**". Repeatable.
+    #[clap(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+}
+
+impl Filter {
+    pub fn matcher(&self) -> anyhow::Result<Matcher> {
+        Ok(Matcher {
+            include: glob_set(&self.include)?,
+            exclude: glob_set(&self.exclude)?,
+        })
+    }
+}
+
+/// A compiled `Filter`
+pub struct Matcher {
+    include: globset::GlobSet,
+    exclude: globset::GlobSet,
+}
+
+impl Matcher {
+    /// Does the post at this path, relative to the content directory, pass the filter
+    pub fn is_match(&self, rel_path: &path::Path) -> bool {
+        (self.include.is_empty() || self.include.is_match(rel_path))
+            && !self.exclude.is_match(rel_path)
+    }
+}
+
+fn glob_set(globs: &[String]) -> anyhow::Result<globset::GlobSet> {
+    let mut builder = globset::GlobSetBuilder::new();
+    for g in globs {
+        builder.add(globset::Glob::new(g).with_context(|| format!("Invalid glob '{g}'"))?);
+    }
+    Ok(builder.build()?)
+}
+
+/// A Markdown page found under a content directory
+#[derive(Debug)]
+pub struct Page {
+    /// Where the Markdown file is
+    pub path: path::PathBuf,
+    /// `path` relative to the directory given on the command line
+    pub rel_path: path::PathBuf,
+    /// `path` relative to the content directory it is in
+    pub content_path: path::PathBuf,
+    /// Language code, empty if we don't know
+    pub lang: String,
+}
+
+/// Find every page under `dir`, recursing into sections.
+///
+/// A directory with an `index.md` is a leaf bundle: the `index.md` is the page and
+/// everything else in there (images, other Markdown) is a resource of that page.
+/// `_index.md` files are Hugo's list pages for a section, so they are skipped
+/// but their directory is searched. Non-Markdown files and anything starting with
+/// a dot are ignored.
+pub fn walk(dir: &path::Path, filter: &Filter) -> anyhow::Result<Vec<Page>> {
+    let matcher = filter.matcher()?;
+    let mut pages = Vec::new();
+    walk_dir(dir, dir, &mut |page| {
+        if matcher.is_match(&page.rel_path) {
+            pages.push(page);
+        }
+    })
+    .with_context(|| format!("Reading {}", dir.display()))?;
+    pages.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+    Ok(pages)
+}
+
+fn walk_dir(
+    root: &path::Path,
+    dir: &path::Path,
+    found: &mut dyn FnMut(Page),
+) -> anyhow::Result<()> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        entries.push(entry);
+    }
+
+    let is_leaf_bundle = entries
+        .iter()
+        .any(|e| is_markdown(&e.path()) && file_stem(&e.path()) == "index");
+    for entry in entries {
+        let p = entry.path();
+        if entry.file_type()?.is_dir() {
+            if !is_leaf_bundle {
+                walk_dir(root, &p, found)?;
+            }
+            continue;
+        }
+        if !is_markdown(&p) {
+            continue;
+        }
+        let stem = file_stem(&p);
+        if stem == "_index" || (is_leaf_bundle && stem != "index") {
+            continue;
+        }
+        let rel_path = p.strip_prefix(root)?.to_path_buf();
+        found(Page {
+            content_path: rel_path.clone(),
+            rel_path,
+            path: p,
+            lang: String::new(),
+        });
+    }
+    Ok(())
+}
+
+fn is_markdown(p: &path::Path) -> bool {
+    p.extension()
+        .map(|ext| MARKDOWN_EXTENSIONS.contains(&ext.to_string_lossy().as_ref()))
+        .unwrap_or(false)
+}
+
+// File name without the extension. Multilingual sites use `index.fr.md`, so only
+// the part before the first dot counts.
+fn file_stem(p: &path::Path) -> String {
+    let name = p.file_name().unwrap_or_default().to_string_lossy();
+    name.split('.').next().unwrap_or_default().to_string()
+}
+
+/// Is this the `index.md` of a leaf bundle
+pub fn is_bundle(rel_path: &path::Path) -> bool {
+    file_stem(rel_path) == "index" && rel_path.parent().is_some_and(|p| !p.as_os_str().is_empty())
+}
+
+/// How Hugo's `site.GetPage` refers to a page: a leaf bundle by its directory,
+/// anything else by its file.
+pub fn page_ref(rel_path: &path::Path) -> String {
+    match rel_path.parent() {
+        Some(parent) if is_bundle(rel_path) => parent.to_string_lossy().to_string(),
+        _ => rel_path.to_string_lossy().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn walk_finds_pages() {
+        let root = std::env::temp_dir().join(format!("hugo-ai-walk-{}", std::process::id()));
+        for f in [
+            "_index.md",
+            "about.md",
+            "notes.txt",
+            ".hidden.md",
+            ".drafts/secret.md",
+            "posts/_index.md",
+            "posts/2023/hello.md",
+            "posts/2023/hello.fr.md",
+            "posts/2023/café.markdown",
+            "posts/2023/bundle/index.md",
+            "posts/2023/bundle/extra.md",
+            "posts/2023/bundle/nested/deep.md",
+            "posts/2024/fr-bundle/index.fr.md",
+        ] {
+            let p = root.join(f);
+            fs::create_dir_all(p.parent().unwrap()).unwrap();
+            fs::write(p, "").unwrap();
+        }
+        let found = |include: &[&str], exclude: &[&str]| -> Vec<String> {
+            let filter = Filter {
+                include: include.iter().map(|s| s.to_string()).collect(),
+                exclude: exclude.iter().map(|s| s.to_string()).collect(),
+            };
+            walk(&root, &filter)
+                .unwrap()
+                .into_iter()
+                .map(|p| p.rel_path.to_string_lossy().replace('\\', "/"))
+                .collect()
+        };
+        let all = found(&[], &[]);
+        let only_2023 = found(&["posts/2023/**"], &["**/*.fr.md"]);
+        let excluded = found(&[], &["posts/**"]);
+        let bad_glob = walk(
+            &root,
+            &Filter {
+                include: vec!["[".to_string()],
+                exclude: vec![],
+            },
+        );
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            all,
+            [
+                "about.md",
+                "posts/2023/bundle/index.md",
+                "posts/2023/café.markdown",
+                "posts/2023/hello.fr.md",
+                "posts/2023/hello.md",
+                "posts/2024/fr-bundle/index.fr.md",
+            ]
+        );
+        assert_eq!(
+            only_2023,
+            [
+                "posts/2023/bundle/index.md",
+                "posts/2023/café.markdown",
+                "posts/2023/hello.md",
+            ]
+        );
+        assert_eq!(excluded, ["about.md"]);
+        assert!(bad_glob.is_err());
+    }
+
+    #[test]
+    fn only_is_include() {
+        #[derive(clap::Parser)]
+        struct Cli {
+            #[clap(flatten)]
+            filter: Filter,
+        }
+        let cli = <Cli as clap::Parser>::try_parse_from([
+            "test",
+            "--only",
+            "posts/2023/**",
+            "--include",
+            "notes/**",
+            "--exclude",
+            "**/*.fr.md",
+        ])
+        .unwrap();
+        assert_eq!(cli.filter.include, ["posts/2023/**", "notes/**"]);
+        let matcher = cli.filter.matcher().unwrap();
+        assert!(matcher.is_match(path::Path::new("posts/2023/hello.md")));
+        assert!(matcher.is_match(path::Path::new("notes/rust.md")));
+        assert!(!matcher.is_match(path::Path::new("posts/2023/hello.fr.md")));
+        assert!(!matcher.is_match(path::Path::new("posts/2024/hello.md")));
+    }
+
+    #[test]
+    fn page_refs() {
+        let r = |p: &str| page_ref(path::Path::new(p));
+        assert_eq!(r("posts/2023/bundle/index.md"), "posts/2023/bundle");
+        assert_eq!(r("posts/fr-bundle/index.fr.md"), "posts/fr-bundle");
+        assert_eq!(r("posts/hello.md"), "posts/hello.md");
+        assert_eq!(r("index.md"), "index.md");
+    }
+}
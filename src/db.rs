@@ -0,0 +1,611 @@
+// MIT License
+// Copyright (c) 2024 Graham King
+
+use std::fs;
+
+use anyhow::Context;
+use rusqlite::OptionalExtension;
+
+#[derive(clap::Subcommand)]
+pub enum Commands {
+    /// Upgrade the database to the latest schema. Every command does this when
+    /// it opens the database, this just lets you do it on its own.
+    Migrate,
+
+    /// Show the schema version, any pending migrations, and what is stored
+    Info,
+}
+
+pub fn run(db_path: &str, cmd: Commands) -> anyhow::Result<()> {
+    match cmd {
+        Commands::Migrate => do_migrate(db_path),
+        Commands::Info => do_info(db_path),
+    }
+}
+
+// The tables as the first version made them.
+// We don't enforce a unique URL because draft articles may not have decided on the slug yet
+const CREATE_ARTICLE_TABLE: &str = r#"
+CREATE TABLE IF NOT EXISTS article (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    title TEXT NOT NULL,
+    url TEXT NOT NULL,
+    date DATETIME NULL,
+    filename TEXT NOT NULL,
+    is_draft BOOL NOT NULL,
+    UNIQUE (filename)
+)
+"#;
+
+const CREATE_CHUNK_TABLE: &str = r#"
+CREATE TABLE IF NOT EXISTS article_chunk (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    article_id INTEGER NOT NULL,
+    chunk_id INTEGER NOT NULL,
+    text TEXT NOT NULL,
+    embed BLOB NULL,
+    FOREIGN KEY (article_id) REFERENCES article (id),
+    UNIQUE (article_id, chunk_id)
+)
+"#;
+
+const CREATE_SIMILARITY_TABLE: &str = r#"
+CREATE TABLE IF NOT EXISTS article_similiarity (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    article_a INTEGER NOT NULL,
+    article_b INTEGER NOT NULL,
+    similarity REAL NOT NULL,
+    FOREIGN KEY (article_a) REFERENCES article (id),
+    FOREIGN KEY (article_b) REFERENCES article (id),
+    UNIQUE(article_a, article_b)
+)
+"#;
+
+struct Migration {
+    description: &'static str,
+    apply: fn(&rusqlite::Connection) -> anyhow::Result<()>,
+}
+
+// Migration N takes the database from `PRAGMA user_version` N to N+1.
+// Only ever add to the end. Once a migration has been released, don't edit it.
+//
+// Databases from before we tracked the version are at 0 but may already have
+// some of these changes, so the early steps check before they act.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "Create article, article_chunk and article_similiarity tables",
+        apply: |db| {
+            db.execute(CREATE_ARTICLE_TABLE, ())?;
+            db.execute(CREATE_CHUNK_TABLE, ())?;
+            db.execute(CREATE_SIMILARITY_TABLE, ())?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Add article section, language and page reference",
+        apply: |db| {
+            add_column(db, "article", "section", "TEXT NOT NULL DEFAULT ''")?;
+            add_column(db, "article", "lang", "TEXT NOT NULL DEFAULT ''")?;
+            add_column(db, "article", "page_ref", "TEXT NOT NULL DEFAULT ''")?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Record the model and vector length of each embedding",
+        apply: |db| {
+            // Before we recorded the model everything was text-embedding-3-small
+            if add_column(db, "article_chunk", "embed_model", "TEXT NULL")? {
+                db.execute(
+                    "UPDATE article_chunk SET embed_model = 'text-embedding-3-small' WHERE embed IS NOT NULL",
+                    (),
+                )?;
+            }
+            if add_column(db, "article_chunk", "embed_dim", "INTEGER NULL")? {
+                db.execute(
+                    "UPDATE article_chunk SET embed_dim = 1536 WHERE embed IS NOT NULL",
+                    (),
+                )?;
+            }
+            // NULL is the model's own length. Only text-embedding-3 can be asked
+            // for less, so a shorter one of those must have been asked for.
+            if add_column(db, "article_chunk", "embed_dim_requested", "INTEGER NULL")? {
+                db.execute(
+                    r#"UPDATE article_chunk SET embed_dim_requested = embed_dim
+                    WHERE embed IS NOT NULL
+                     AND ((embed_model = 'text-embedding-3-small' AND embed_dim != 1536)
+                      OR (embed_model = 'text-embedding-3-large' AND embed_dim != 3072))"#,
+                    (),
+                )?;
+            }
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Store a hash of each chunk's text",
+        apply: |db| {
+            // Filled in by the next gather
+            add_column(db, "article_chunk", "text_hash", "TEXT NULL")?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Rename article_similiarity to article_similarity",
+        apply: |db| {
+            db.execute(
+                "ALTER TABLE article_similiarity RENAME TO article_similarity",
+                (),
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Track which articles changed since the last calc",
+        apply: |db| {
+            db.execute(
+                "CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value TEXT NOT NULL)",
+                (),
+            )?;
+            add_column(db, "article", "embed_version", "INTEGER NOT NULL DEFAULT 0")?;
+            // So the next calc compares everything once
+            db.execute("UPDATE article SET embed_version = 1", ())?;
+            set_meta(db, EMBED_VERSION, "1")?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Store which strategy calculated each similarity score",
+        apply: |db| {
+            // The only way to change a UNIQUE constraint is a new table
+            db.execute_batch(
+                r#"
+                CREATE TABLE article_similarity_new (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    article_a INTEGER NOT NULL,
+                    article_b INTEGER NOT NULL,
+                    strategy TEXT NOT NULL,
+                    similarity REAL NOT NULL,
+                    FOREIGN KEY (article_a) REFERENCES article (id),
+                    FOREIGN KEY (article_b) REFERENCES article (id),
+                    UNIQUE(article_a, article_b, strategy)
+                );
+                INSERT INTO article_similarity_new (id, article_a, article_b, strategy, similarity)
+                    SELECT id, article_a, article_b, 'mean-pairs', similarity FROM article_similarity;
+                DROP TABLE article_similarity;
+                ALTER TABLE article_similarity_new RENAME TO article_similarity;
+                UPDATE meta SET key = 'calc_version:mean-pairs' WHERE key = 'calc_version';
+                "#,
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Record when we wrote each front matter field",
+        apply: |db| {
+            db.execute(
+                r#"CREATE TABLE generated (
+                    filename TEXT NOT NULL,
+                    field TEXT NOT NULL,
+                    generated_at DATETIME NOT NULL,
+                    UNIQUE(filename, field)
+                )"#,
+                (),
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Store article tags and front matter, for filtering related posts",
+        apply: |db| {
+            // JSON. Filled in by the next gather.
+            add_column(db, "article", "tags", "TEXT NOT NULL DEFAULT '[]'")?;
+            add_column(db, "article", "params", "TEXT NOT NULL DEFAULT '{}'")?;
+            Ok(())
+        },
+    },
+];
+
+// Meta key of the counter behind article.embed_version
+const EMBED_VERSION: &str = "embed_version";
+
+/// Open the database, creating it or upgrading it to the latest schema
+pub fn open(db_path: &str) -> anyhow::Result<rusqlite::Connection> {
+    let mut db_conn = connect(db_path)?;
+    migrate(&mut db_conn)?;
+    Ok(db_conn)
+}
+
+/// Open an existing database without changing it. It must already have the
+/// latest schema, as we can't migrate it.
+pub fn open_read_only(db_path: &str) -> anyhow::Result<rusqlite::Connection> {
+    let db_conn =
+        rusqlite::Connection::open_with_flags(db_path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(|| db_path.to_string())?;
+    let version = schema_version(&db_conn)?;
+    if version > MIGRATIONS.len() {
+        anyhow::bail!(
+            "Database schema version {version} is newer than this hugo-ai knows about ({}). \
+            Upgrade hugo-ai.",
+            MIGRATIONS.len()
+        );
+    }
+    if version < MIGRATIONS.len() {
+        anyhow::bail!(
+            "{db_path} has schema version {version} of {}. Run `hugo-ai db migrate` first.",
+            MIGRATIONS.len()
+        );
+    }
+    Ok(db_conn)
+}
+
+/// A new database that only lives as long as the connection, for tests
+#[cfg(test)]
+pub fn open_in_memory() -> anyhow::Result<rusqlite::Connection> {
+    let mut db_conn = rusqlite::Connection::open_in_memory()?;
+    migrate(&mut db_conn)?;
+    Ok(db_conn)
+}
+
+fn connect(db_path: &str) -> anyhow::Result<rusqlite::Connection> {
+    rusqlite::Connection::open(db_path).with_context(|| db_path.to_string())
+}
+
+// Apply the pending migrations, all or nothing. Returns how many there were.
+fn migrate(db_conn: &mut rusqlite::Connection) -> anyhow::Result<usize> {
+    let version = schema_version(db_conn)?;
+    if version > MIGRATIONS.len() {
+        anyhow::bail!(
+            "Database schema version {version} is newer than this hugo-ai knows about ({}). \
+            Upgrade hugo-ai.",
+            MIGRATIONS.len()
+        );
+    }
+    if version == MIGRATIONS.len() {
+        return Ok(0);
+    }
+    let tx = db_conn.transaction()?;
+    for (idx, m) in MIGRATIONS.iter().enumerate().skip(version) {
+        (m.apply)(&tx).with_context(|| format!("Migration {}: {}", idx + 1, m.description))?;
+    }
+    // PRAGMA doesn't take bound parameters
+    tx.execute_batch(&format!("PRAGMA user_version = {}", MIGRATIONS.len()))?;
+    tx.commit()?;
+    Ok(MIGRATIONS.len() - version)
+}
+
+fn schema_version(db_conn: &rusqlite::Connection) -> anyhow::Result<usize> {
+    Ok(db_conn.query_row("PRAGMA user_version", (), |row| row.get(0))?)
+}
+
+pub fn get_meta(db_conn: &rusqlite::Connection, key: &str) -> anyhow::Result<Option<String>> {
+    Ok(db_conn
+        .query_row("SELECT value FROM meta WHERE key = ?1", [key], |row| {
+            row.get(0)
+        })
+        .optional()?)
+}
+
+pub fn set_meta(db_conn: &rusqlite::Connection, key: &str, value: &str) -> anyhow::Result<()> {
+    db_conn.execute(
+        "INSERT INTO meta (key, value) VALUES (?1, ?2) ON CONFLICT DO UPDATE SET value = excluded.value",
+        (key, value),
+    )?;
+    Ok(())
+}
+
+/// The most recent article.embed_version handed out
+pub fn embed_version(db_conn: &rusqlite::Connection) -> anyhow::Result<u64> {
+    Ok(get_meta(db_conn, EMBED_VERSION)?
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0))
+}
+
+/// Record that an article's embeddings (or whether it is a draft) changed,
+/// so the next calc compares it again
+pub fn mark_changed(db_conn: &rusqlite::Connection, article_id: usize) -> anyhow::Result<()> {
+    let version = embed_version(db_conn)? + 1;
+    set_meta(db_conn, EMBED_VERSION, &version.to_string())?;
+    db_conn.execute(
+        "UPDATE article SET embed_version = ?1 WHERE id = ?2",
+        (version, article_id),
+    )?;
+    Ok(())
+}
+
+// Add the column if it isn't there yet. Returns true if it was added.
+fn add_column(
+    db_conn: &rusqlite::Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> anyhow::Result<bool> {
+    if has_column(db_conn, table, column)? {
+        return Ok(false);
+    }
+    db_conn.execute(
+        &format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"),
+        (),
+    )?;
+    Ok(true)
+}
+
+fn has_column(db_conn: &rusqlite::Connection, table: &str, column: &str) -> anyhow::Result<bool> {
+    let mut stmt = db_conn.prepare(&format!("PRAGMA table_info({table})"))?;
+    let mut rows = stmt.query(())?;
+    while let Some(row) = rows.next()? {
+        if row.get::<_, String>(1)? == column {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn do_migrate(db_path: &str) -> anyhow::Result<()> {
+    let mut db_conn = connect(db_path)?;
+    let from = schema_version(&db_conn)?;
+    let applied = migrate(&mut db_conn)?;
+    if applied == 0 {
+        println!("{db_path} is up to date, schema version {from}");
+        return Ok(());
+    }
+    for (idx, m) in MIGRATIONS.iter().enumerate().skip(from) {
+        println!("{}: {}", idx + 1, m.description);
+    }
+    println!(
+        "Migrated {db_path} from schema version {from} to {}",
+        MIGRATIONS.len()
+    );
+    Ok(())
+}
+
+fn do_info(db_path: &str) -> anyhow::Result<()> {
+    if !fs::exists(db_path)? {
+        println!("{db_path} doesn't exist yet. Run `hugo-ai similar gather` to create it.");
+        return Ok(());
+    }
+    let db_conn = connect(db_path)?;
+    let version = schema_version(&db_conn)?;
+    println!(
+        "Database: {db_path} ({} KiB)",
+        fs::metadata(db_path)?.len() / 1024
+    );
+    println!("Schema version: {version} of {}", MIGRATIONS.len());
+    if version < MIGRATIONS.len() {
+        println!("Pending migrations:");
+        for (idx, m) in MIGRATIONS.iter().enumerate().skip(version) {
+            println!("  {}: {}", idx + 1, m.description);
+        }
+        println!("They are applied the next time any command uses the database, or run `hugo-ai db migrate`.");
+        // The queries below expect the latest schema
+        return Ok(());
+    }
+
+    let count =
+        |sql: &str| -> anyhow::Result<usize> { Ok(db_conn.query_row(sql, (), |row| row.get(0))?) };
+    println!(
+        "Articles: {} ({} drafts)",
+        count("SELECT COUNT(*) FROM article")?,
+        count("SELECT COUNT(*) FROM article WHERE is_draft")?
+    );
+    println!(
+        "Chunks: {} ({} embedded)",
+        count("SELECT COUNT(*) FROM article_chunk")?,
+        count("SELECT COUNT(*) FROM article_chunk WHERE embed IS NOT NULL")?
+    );
+    let mut stmt = db_conn.prepare(
+        r#"SELECT embed_model, embed_dim, COUNT(*) FROM article_chunk
+        WHERE embed IS NOT NULL GROUP BY 1, 2 ORDER BY 1, 2"#,
+    )?;
+    let rows = stmt.query_map((), |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, usize>(1)?,
+            row.get::<_, usize>(2)?,
+        ))
+    })?;
+    for row in rows {
+        let (model, dim, n) = row?;
+        println!("  {model}/{dim}: {n} chunks");
+    }
+    println!(
+        "Similarity scores: {}",
+        count("SELECT COUNT(*) FROM article_similarity")?
+    );
+    let mut stmt = db_conn
+        .prepare("SELECT strategy, COUNT(*) FROM article_similarity GROUP BY 1 ORDER BY 1")?;
+    let rows = stmt.query_map((), |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, usize>(1)?))
+    })?;
+    for row in rows {
+        let (strategy, n) = row?;
+        println!("  {strategy}: {n}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A database as the first version left it: the tables, no user_version
+    fn baseline() -> rusqlite::Connection {
+        let db_conn = rusqlite::Connection::open_in_memory().unwrap();
+        for sql in [
+            CREATE_ARTICLE_TABLE,
+            CREATE_CHUNK_TABLE,
+            CREATE_SIMILARITY_TABLE,
+        ] {
+            db_conn.execute(sql, ()).unwrap();
+        }
+        db_conn
+            .execute_batch(
+                r#"
+                INSERT INTO article (id, title, url, date, filename, is_draft) VALUES
+                    (1, 'Über Rust', '/rust/', '2023-01-02T00:00:00Z', 'posts/rust.md', 0),
+                    (2, 'Drafty', '', NULL, 'posts/draft.md', 1);
+                INSERT INTO article_chunk (article_id, chunk_id, text, embed) VALUES
+                    (1, 0, 'Erster Teil', x'000000000000F03F'),
+                    (1, 1, 'Zweiter Teil', NULL),
+                    (2, 0, 'draft', x'0000000000000040');
+                INSERT INTO article_similiarity (article_a, article_b, similarity)
+                    VALUES (1, 2, 0.5);
+                "#,
+            )
+            .unwrap();
+        db_conn
+    }
+
+    // Every table's schema and rows, and the schema version
+    fn dump(db_conn: &rusqlite::Connection) -> Vec<String> {
+        let mut out = vec![format!("version {}", schema_version(db_conn).unwrap())];
+        let mut stmt = db_conn
+            .prepare("SELECT name, sql FROM sqlite_master WHERE type = 'table' ORDER BY name")
+            .unwrap();
+        let tables: Vec<(String, String)> = stmt
+            .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        for (name, sql) in tables {
+            out.push(sql);
+            let mut stmt = db_conn
+                .prepare(&format!("SELECT * FROM {name} ORDER BY 1, 2"))
+                .unwrap();
+            let columns = stmt.column_count();
+            let mut rows = stmt.query(()).unwrap();
+            while let Some(row) = rows.next().unwrap() {
+                let values: Vec<String> = (0..columns)
+                    .map(|i| format!("{:?}", row.get_ref(i).unwrap()))
+                    .collect();
+                out.push(format!("{name}: {}", values.join(", ")));
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn baseline_to_latest() {
+        let mut db_conn = baseline();
+        assert_eq!(migrate(&mut db_conn).unwrap(), MIGRATIONS.len());
+        assert_eq!(schema_version(&db_conn).unwrap(), MIGRATIONS.len());
+
+        let mut stmt = db_conn
+            .prepare(
+                r#"SELECT article_id, chunk_id, text, embed, embed_model, embed_dim,
+                embed_dim_requested, text_hash
+                FROM article_chunk ORDER BY article_id, chunk_id"#,
+            )
+            .unwrap();
+        type Chunk = (
+            usize,
+            usize,
+            String,
+            Option<Vec<u8>>,
+            Option<String>,
+            Option<usize>,
+            Option<usize>,
+            Option<String>,
+        );
+        let chunks: Vec<Chunk> = stmt
+            .query_map((), |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                    row.get(6)?,
+                    row.get(7)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let small = Some("text-embedding-3-small".to_string());
+        assert_eq!(
+            chunks,
+            vec![
+                (
+                    1,
+                    0,
+                    "Erster Teil".to_string(),
+                    Some(1.0f64.to_le_bytes().to_vec()),
+                    small.clone(),
+                    Some(1536),
+                    None,
+                    None
+                ),
+                (
+                    1,
+                    1,
+                    "Zweiter Teil".to_string(),
+                    None,
+                    None,
+                    None,
+                    None,
+                    None
+                ),
+                (
+                    2,
+                    0,
+                    "draft".to_string(),
+                    Some(2.0f64.to_le_bytes().to_vec()),
+                    small,
+                    Some(1536),
+                    None,
+                    None
+                ),
+            ]
+        );
+
+        let article: (String, String, String, usize, String) = db_conn
+            .query_row(
+                "SELECT title, section, page_ref, embed_version, tags FROM article WHERE id = 1",
+                (),
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                    ))
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            article,
+            (
+                "Über Rust".to_string(),
+                String::new(),
+                String::new(),
+                1,
+                "[]".to_string()
+            )
+        );
+        let score: (usize, usize, String, f64) = db_conn
+            .query_row(
+                "SELECT article_a, article_b, strategy, similarity FROM article_similarity",
+                (),
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(score, (1, 2, "mean-pairs".to_string(), 0.5));
+        assert_eq!(embed_version(&db_conn).unwrap(), 1);
+    }
+
+    #[test]
+    fn migrating_again_changes_nothing() {
+        let mut db_conn = baseline();
+        migrate(&mut db_conn).unwrap();
+        let before = dump(&db_conn);
+        assert_eq!(migrate(&mut db_conn).unwrap(), 0);
+        assert_eq!(dump(&db_conn), before);
+
+        let mut fresh = rusqlite::Connection::open_in_memory().unwrap();
+        migrate(&mut fresh).unwrap();
+        let before = dump(&fresh);
+        assert_eq!(migrate(&mut fresh).unwrap(), 0);
+        assert_eq!(dump(&fresh), before);
+    }
+}
@@ -0,0 +1,191 @@
+// MIT License
+// Copyright (c) 2024 Graham King
+
+use crate::api;
+use crate::ollama;
+use crate::openai;
+
+// How many chunks to send in each request, and how many requests to have in flight
+const DEFAULT_BATCH_SIZE: usize = 64;
+const DEFAULT_WORKERS: usize = 4;
+
+/// Turns text into a vector. Shared between worker threads.
+pub trait Embedder: Send + Sync {
+    /// Recorded with every embedding. Vectors from different models can't be compared.
+    fn model(&self) -> &str;
+
+    /// The vector length we asked for, if we asked
+    fn dimensions(&self) -> Option<usize>;
+
+    /// One vector per input, in the same order
+    fn embed(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f64>>>;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Provider {
+    /// OpenAI's API. Needs OPENAI_API_KEY.
+    #[default]
+    Openai,
+    /// A local Ollama server
+    Ollama,
+    /// Anything that copies OpenAI's embeddings API: llama.cpp server, LM Studio, vLLM, ...
+    OpenaiCompatible,
+}
+
+/// Which embedding model to use. The `[embed]` section of the config file,
+/// with command line flags taking priority.
+#[derive(clap::Args, serde::Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Embedding API [default: openai]
+    #[clap(long)]
+    pub provider: Option<Provider>,
+
+    /// Embedding model. Defaults to text-embedding-3-small for OpenAI and
+    /// nomic-embed-text for Ollama.
+    #[clap(long)]
+    pub model: Option<String>,
+
+    /// Length of the embedding vector, for models that let you choose
+    /// (OpenAI's text-embedding-3 family)
+    #[clap(long)]
+    pub dimensions: Option<usize>,
+
+    /// Root URL of the API, e.g. http://localhost:8080/v1 for a llama.cpp server
+    #[clap(long, value_name = "URL")]
+    pub base_url: Option<String>,
+
+    /// Name of the environment variable with the API key [default: OPENAI_API_KEY]
+    #[clap(long, value_name = "VAR")]
+    pub api_key_env: Option<String>,
+
+    /// Chunks to send in each request [default: 64]
+    #[clap(long)]
+    pub batch_size: Option<usize>,
+
+    /// Requests to run at the same time [default: 4]
+    #[clap(long)]
+    pub workers: Option<usize>,
+}
+
+impl Settings {
+    /// Fill in anything not set here from `other`
+    pub fn or(self, other: &Settings) -> Settings {
+        Settings {
+            provider: self.provider.or(other.provider),
+            model: self.model.or_else(|| other.model.clone()),
+            dimensions: self.dimensions.or(other.dimensions),
+            base_url: self.base_url.or_else(|| other.base_url.clone()),
+            api_key_env: self.api_key_env.or_else(|| other.api_key_env.clone()),
+            batch_size: self.batch_size.or(other.batch_size),
+            workers: self.workers.or(other.workers),
+        }
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.batch_size.unwrap_or(DEFAULT_BATCH_SIZE).max(1)
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers.unwrap_or(DEFAULT_WORKERS).max(1)
+    }
+
+    pub fn build(&self, http: &api::Settings) -> anyhow::Result<Box<dyn Embedder>> {
+        let key_var = self.api_key_env.as_deref().unwrap_or("OPENAI_API_KEY");
+        Ok(match self.provider.unwrap_or_default() {
+            Provider::Openai => {
+                let Ok(api_key) = std::env::var(key_var) else {
+                    return Err(anyhow::anyhow!("Set variable {key_var} to your key"));
+                };
+                Box::new(OpenAi {
+                    client: api::Client::new(http)?,
+                    base_url: self.base_url.as_deref().unwrap_or(openai::API_BASE).into(),
+                    api_key: Some(api_key),
+                    model: self.model.as_deref().unwrap_or(openai::EMBED_MODEL).into(),
+                    dimensions: self.dimensions,
+                })
+            }
+            Provider::OpenaiCompatible => {
+                let Some(base_url) = self.base_url.clone() else {
+                    return Err(anyhow::anyhow!(
+                        "openai-compatible provider needs a base_url"
+                    ));
+                };
+                let Some(model) = self.model.clone() else {
+                    return Err(anyhow::anyhow!("openai-compatible provider needs a model"));
+                };
+                Box::new(OpenAi {
+                    client: api::Client::new(http)?,
+                    base_url,
+                    // Local servers usually don't want one
+                    api_key: std::env::var(key_var).ok(),
+                    model,
+                    dimensions: self.dimensions,
+                })
+            }
+            Provider::Ollama => {
+                if self.dimensions.is_some() {
+                    return Err(anyhow::anyhow!("Ollama can't change embedding dimensions"));
+                }
+                Box::new(Ollama {
+                    client: api::Client::new(http)?,
+                    base_url: self.base_url.as_deref().unwrap_or(ollama::API_BASE).into(),
+                    model: self.model.as_deref().unwrap_or(ollama::EMBED_MODEL).into(),
+                })
+            }
+        })
+    }
+}
+
+// Each holds one Client so connections are re-used across requests and threads
+
+/// OpenAI, or a server with the same API
+struct OpenAi {
+    client: api::Client,
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+    dimensions: Option<usize>,
+}
+
+impl Embedder for OpenAi {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn dimensions(&self) -> Option<usize> {
+        self.dimensions
+    }
+
+    fn embed(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f64>>> {
+        openai::embed(
+            &self.client,
+            &self.base_url,
+            self.api_key.as_deref(),
+            &self.model,
+            self.dimensions,
+            texts,
+        )
+    }
+}
+
+struct Ollama {
+    client: api::Client,
+    base_url: String,
+    model: String,
+}
+
+impl Embedder for Ollama {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn dimensions(&self) -> Option<usize> {
+        None
+    }
+
+    fn embed(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f64>>> {
+        ollama::embed(&self.client, &self.base_url, &self.model, texts)
+    }
+}
@@ -0,0 +1,258 @@
+// MIT License
+// Copyright (c) 2024 Graham King
+
+use std::fs;
+use std::path;
+
+use anyhow::Context;
+
+use crate::article::Article;
+use crate::db;
+use crate::index::Index;
+
+const INDEX_NAME: &str = "search-index";
+
+// Start of the binary format, then a version byte
+const MAGIC: &[u8; 4] = b"HAIX";
+const VERSION: u8 = 1;
+
+#[derive(clap::Subcommand)]
+pub enum Commands {
+    /// Write article metadata and int8 centroid embeddings into the site,
+    /// for searching in the browser
+    SearchIndex {
+        /// The Hugo site root
+        directory: String,
+
+        /// Which site directory to write into. Binary only goes in static.
+        #[clap(long, value_enum, default_value_t = Target::Static)]
+        into: Target,
+
+        #[clap(long, value_enum, default_value_t = Format::Json)]
+        format: Format,
+
+        /// Write here instead of <directory>/<into>/search-index.<json|bin>
+        #[clap(long, value_name = "PATH")]
+        output: Option<String>,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Target {
+    /// Served as is, fetch it from JavaScript
+    Static,
+    /// Available to templates as site.Data.search_index
+    Data,
+}
+
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Format {
+    Json,
+    /// Smaller. See the README for the layout.
+    Binary,
+}
+
+pub fn run(db_path: &str, cmd: Commands) -> anyhow::Result<()> {
+    match cmd {
+        Commands::SearchIndex {
+            directory,
+            into,
+            format,
+            output,
+        } => do_search_index(db_path, &directory, into, format, output),
+    }
+}
+
+fn do_search_index(
+    db_path: &str,
+    directory: &str,
+    into: Target,
+    format: Format,
+    output: Option<String>,
+) -> anyhow::Result<()> {
+    if into == Target::Data && format == Format::Binary && output.is_none() {
+        anyhow::bail!("Hugo data files must be JSON, YAML or TOML. Use --into static for binary.");
+    }
+    let db_conn = db::open(db_path)?;
+    let index = Index::load(&db_conn)?;
+
+    let mut articles = Vec::new();
+    let mut embeddings = Vec::new();
+    for a in index.articles.iter() {
+        let Some(centroid) = index.centroid(a.id) else {
+            // Not embedded yet
+            continue;
+        };
+        articles.push(metadata(a));
+        embeddings.push(quantize(centroid));
+    }
+
+    let out = match output {
+        Some(o) => path::PathBuf::from(o),
+        None => {
+            let ext = match format {
+                Format::Json => "json",
+                Format::Binary => "bin",
+            };
+            let (sub, name) = match into {
+                Target::Static => ("static", INDEX_NAME.to_string()),
+                // Data file names become template keys, where dashes are awkward
+                Target::Data => ("data", INDEX_NAME.replace('-', "_")),
+            };
+            path::Path::new(directory)
+                .join(sub)
+                .join(format!("{name}.{ext}"))
+        }
+    };
+    let count = embeddings.len();
+    let bytes = match format {
+        Format::Json => {
+            let embedded: Vec<serde_json::Value> = articles
+                .into_iter()
+                .zip(embeddings)
+                .map(|(mut a, (scale, q))| {
+                    a["scale"] = serde_json::json!(scale);
+                    a["embedding"] = serde_json::json!(q);
+                    a
+                })
+                .collect();
+            serde_json::to_vec(&serde_json::json!({
+                "model": index.model,
+                "dim": index.dim,
+                "articles": embedded,
+            }))?
+        }
+        Format::Binary => {
+            let header = serde_json::to_vec(&serde_json::json!({
+                "model": index.model,
+                "dim": index.dim,
+                "articles": articles,
+            }))?;
+            let mut b = Vec::with_capacity(17 + header.len() + embeddings.len() * (4 + index.dim));
+            b.extend_from_slice(MAGIC);
+            b.push(VERSION);
+            b.extend_from_slice(&(index.dim as u32).to_le_bytes());
+            b.extend_from_slice(&(embeddings.len() as u32).to_le_bytes());
+            b.extend_from_slice(&(header.len() as u32).to_le_bytes());
+            b.extend_from_slice(&header);
+            for (scale, q) in embeddings.iter() {
+                b.extend_from_slice(&scale.to_le_bytes());
+                b.extend(q.iter().map(|x| *x as u8));
+            }
+            b
+        }
+    };
+    if let Some(parent) = out.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&out, &bytes).with_context(|| format!("Writing {}", out.display()))?;
+    println!(
+        "Wrote {} articles embedded with {} to {} ({} KiB)",
+        count,
+        index.model,
+        out.display(),
+        bytes.len().div_ceil(1024)
+    );
+    Ok(())
+}
+
+fn metadata(a: &Article) -> serde_json::Value {
+    serde_json::json!({
+        "title": a.title,
+        "url": a.url,
+        "page_ref": a.page_ref,
+        "date": a.date.map(|d| d.to_rfc3339()),
+        "section": a.section,
+        "lang": a.lang,
+        "tags": a.tags,
+    })
+}
+
+/// Map each value to -127..=127. Multiply by the returned scale to get it back.
+fn quantize(v: &[f64]) -> (f32, Vec<i8>) {
+    let max = v.iter().fold(0.0, |m: f64, x| m.max(x.abs()));
+    if max == 0.0 {
+        return (0.0, vec![0; v.len()]);
+    }
+    let scale = max / 127.0;
+    let q = v.iter().map(|x| (x / scale).round() as i8).collect();
+    (scale as f32, q)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantize_round_trip() {
+        let v = [0.5, -0.25, 0.1, -0.5, 0.0, 0.333];
+        let (scale, q) = quantize(&v);
+        assert_eq!(q[0], 127);
+        assert_eq!(q[3], -127);
+        assert_eq!(q[4], 0);
+        for (x, qx) in v.iter().zip(q.iter()) {
+            let back = *qx as f64 * scale as f64;
+            assert!(
+                (back - x).abs() <= scale as f64 / 2.0 + 1e-6,
+                "{x} came back {back}"
+            );
+        }
+
+        assert_eq!(quantize(&[0.0, 0.0, 0.0]), (0.0, vec![0, 0, 0]));
+        assert_eq!(quantize(&[-2.0, 1.0]).1, [-127, 64]);
+    }
+
+    #[test]
+    fn binary_layout() {
+        let dir = std::env::temp_dir().join(format!("hugo-ai-export-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.join("test.db");
+        let db_path = db_path.to_str().unwrap();
+        let db_conn = db::open(db_path).unwrap();
+        for (id, name, embed) in [(1, "one", [3.0, 4.0]), (2, "two", [0.0, -1.0])] {
+            db_conn
+                .execute(
+                    r#"INSERT INTO article (id, title, url, filename, is_draft, section, lang, page_ref)
+                    VALUES (?1, ?2, '', ?3, 0, 'posts', 'en', ?4)"#,
+                    (id, name, format!("{name}.md"), format!("/posts/{name}")),
+                )
+                .unwrap();
+            db_conn
+                .execute(
+                    r#"INSERT INTO article_chunk (article_id, chunk_id, text, embed, embed_model, embed_dim)
+                    VALUES (?1, 0, 'text', ?2, 'fake', 2)"#,
+                    (id, crate::similar::f64_vec_to_u8_vec(embed.to_vec())),
+                )
+                .unwrap();
+        }
+        drop(db_conn);
+        do_search_index(
+            db_path,
+            dir.to_str().unwrap(),
+            Target::Static,
+            Format::Binary,
+            None,
+        )
+        .unwrap();
+        let b = fs::read(dir.join("static/search-index.bin")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let u32_at = |pos: usize| u32::from_le_bytes(b[pos..pos + 4].try_into().unwrap()) as usize;
+        assert_eq!(&b[..4], MAGIC);
+        assert_eq!(b[4], VERSION);
+        let (dim, count, header_len) = (u32_at(5), u32_at(9), u32_at(13));
+        assert_eq!((dim, count), (2, 2));
+        let header: serde_json::Value = serde_json::from_slice(&b[17..17 + header_len]).unwrap();
+        assert_eq!(header["model"], "fake");
+        assert_eq!(header["dim"], 2);
+        assert_eq!(header["articles"][1]["page_ref"], "/posts/two");
+
+        // Then per article a little-endian f32 scale and `dim` int8s
+        let embeddings = &b[17 + header_len..];
+        assert_eq!(embeddings.len(), count * (4 + dim));
+        let scale = f32::from_le_bytes(embeddings[..4].try_into().unwrap());
+        assert!((scale - 0.8 / 127.0).abs() < 1e-6);
+        assert_eq!(embeddings[4..6], [95, 127]);
+        assert_eq!(embeddings[10..12], [0, (-127i8) as u8]);
+    }
+}
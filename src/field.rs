@@ -2,75 +2,215 @@
 // Copyright (c) 2024 Graham King
 
 use anyhow::Context;
-use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
 use std::io;
 
+use crate::api;
+use crate::chunk;
 use crate::claude;
-use crate::front_matter::FrontMatter;
+use crate::content;
+use crate::db;
+use crate::front_matter::Post;
+use crate::generated;
+use crate::generator::{self, Generator};
+use crate::normalize;
 use crate::openai;
+use crate::similar;
+use crate::similar::strategy::Scoring;
+use crate::site::Site;
+use crate::tokens;
+
+// For map-reduce, the prompts for each part of a long post. The field's own
+// prompts then run on these notes.
+const SECTION_PROMPTS: super::Prompts = super::Prompts {
+    system: "",
+    user: "This is one part of a longer blog post. Summarize it in a short paragraph. Keep the key points, and the author's tone and point of view.",
+    examples: &[],
+};
+
+// Examples are cut to this, so a few of them don't crowd out the post
+const EXAMPLE_TOKENS: usize = 1_000;
+
+// How many similar posts {{related_titles}} lists
+const RELATED_TITLES: usize = 5;
+
+/// What to do with a post that's longer than the model can read
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LongPostMode {
+    /// Send as much of the start of the post as fits
+    #[default]
+    Truncate,
+    /// Summarize each part of the post, then send those summaries
+    MapReduce,
+}
+
+/// Handling posts too long for the model. The `[long_posts]` section of the
+/// config file, with command line flags taking priority.
+#[derive(clap::Args, serde::Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+#[group(id = "long_posts")]
+pub struct LongPosts {
+    /// What to do with posts longer than the model can read, or than --max-input-tokens
+    /// [default: truncate]
+    #[clap(long = "long-posts", value_enum, value_name = "MODE")]
+    pub mode: Option<LongPostMode>,
+
+    /// Send at most this many tokens of a post per request, to keep costs down
+    /// [default: as many as the model takes]
+    #[clap(long, value_name = "TOKENS")]
+    pub max_input_tokens: Option<usize>,
+}
+
+impl LongPosts {
+    /// Fill in anything not set here from `other`
+    pub fn or(self, other: &LongPosts) -> LongPosts {
+        LongPosts {
+            mode: self.mode.or(other.mode),
+            max_input_tokens: self.max_input_tokens.or(other.max_input_tokens),
+        }
+    }
+
+    pub fn mode(&self) -> LongPostMode {
+        self.mode.unwrap_or_default()
+    }
+
+    /// Most tokens of post we can send `model` along with `prompts`
+    fn budget(&self, model: &str, prompts: super::Prompts) -> usize {
+        let prompt_tokens = tokens::count_for(model, prompts.system)
+            + tokens::count_for(model, prompts.user)
+            + prompts
+                .examples
+                .iter()
+                .map(|e| {
+                    tokens::count_for(model, &e.user)
+                        + tokens::count_for(model, &e.text)
+                        + tokens::count_for(model, &e.answer)
+                })
+                .sum::<usize>();
+        let limit = tokens::input_limit(model).saturating_sub(prompt_tokens);
+        self.max_input_tokens.map_or(limit, |m| m.min(limit)).max(1)
+    }
+}
 
 /// Fill a meta-data/front-matter field on each blog post using a set of prompts and a model
+#[allow(clippy::too_many_arguments)]
 pub fn run(
-    // The directory to look for Hugo Markdown posts in
+    // Where we remember when each field was written
+    db_path: &str,
+    // The Hugo site root, or a directory of Markdown posts
     dir: &str,
+    // Which posts in `dir` to consider
+    filter: &content::Filter,
+    // Whether to replace the field on posts that already have it
+    refresh: &generated::Refresh,
     // The magic
     model: super::ModelChoice,
     // If true backup the file to a .BAK
     is_backup: bool,
-    // The name of the front-matter field to populate
-    field_name: &'static str,
-    // System and user prompts to send to the model
-    prompts: super::Prompts,
-    // Ignore posts shorter than this
-    min_len: usize,
+    // The front-matter field to populate, and how
+    generator: &Generator,
+    // How to turn the Markdown into text for the model
+    normalize: &normalize::Settings,
+    // What to do with posts too long for the model
+    long_posts: &LongPosts,
+    // Timeouts and retries
+    http: &api::Settings,
 ) -> anyhow::Result<()> {
-    let posts: Vec<fs::DirEntry> = fs::read_dir(dir)?.map(|x| x.unwrap()).collect();
+    let client = api::Client::new(http)?;
+    let db_conn = db::open(db_path)?;
+    let site = Site::load(dir)?;
+    let posts = site.pages(filter)?;
     println!("Processing {} posts", posts.len());
 
+    let field_name = generator.field.as_str();
+    let model_name = model.name();
+    let example_posts = examples(&db_conn, &site, generator, normalize, model_name)?;
+    if !example_posts.is_empty() {
+        println!(
+            "Up to {} examples per post, from {}",
+            generator.examples,
+            example_posts
+                .iter()
+                .map(|(filename, _)| filename.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    // Posts that didn't fit, their size in tokens and how much would
+    let mut too_long = Vec::new();
     let mut written_count = 0;
-    for entry in posts.into_iter() {
-        let filepath = entry.path();
+    for page in posts.into_iter() {
+        let section = site.section(&page);
+        let filepath = page.path;
+        let filename = page.rel_path.to_string_lossy();
         let s = fs::read_to_string(&filepath)?;
-        let front_matter_vec = FrontMatter::select(&s);
-        let mut fm: HashMap<String, serde_yaml::Value> =
-            serde_yaml::from_str(&front_matter_vec.join("\n"))
-                .context(filepath.display().to_string())?;
-        if matches!(fm.get("draft"), Some(serde_yaml::Value::Bool(true))) {
+        let post = Post::split(&s).context(filepath.display().to_string())?;
+        let fm: serde_json::Map<String, serde_json::Value> =
+            post.parse().context(filepath.display().to_string())?;
+        if matches!(fm.get("draft"), Some(serde_json::Value::Bool(true))) {
             // Don't process drafts as they will change
             continue;
         }
-        if fm.contains_key(field_name) {
+        if fm.contains_key(field_name)
+            && !refresh.should_replace(&db_conn, &filename, field_name)?
+        {
             // Skip if it already has one
             continue;
         }
 
-        let body: String = s
-            .lines()
-            .skip(front_matter_vec.len() + 2) // Add the two dashes lines we must also skip
-            .collect::<Vec<&str>>()
-            .join("\n");
-        if body.len() < min_len {
+        let body = &normalize.text(post.body);
+        if body.len() < generator.min_len() {
             // Too short to be interesting
             continue;
         }
 
-        use super::ModelChoice::*;
-        let maybe = match model {
-            Gpt4o => openai::message(openai::CHAT_MODEL_BIG, &body, prompts),
-            Gpt4oMini => openai::message(openai::CHAT_MODEL_SMALL, &body, prompts),
-            Claude35Sonnet => claude::message(claude::CHAT_MODEL_BIG, &body, prompts),
-            Claude3Haiku => claude::message(claude::CHAT_MODEL_SMALL, &body, prompts),
+        let vars = generator::variables(&fm, &section, &related_titles(&db_conn, &filename)?);
+        let (system, user) = generator.render(&vars);
+        // Not the post itself, if it's being refreshed
+        let examples: Vec<super::Example> = example_posts
+            .iter()
+            .filter(|(f, _)| *f != filename)
+            .take(generator.examples)
+            .map(|(_, e)| e.clone())
+            .collect();
+        let prompts = super::Prompts {
+            system: &system,
+            user: &user,
+            examples: &examples,
+        };
+        let budget = long_posts.budget(model_name, prompts);
+        let body_tokens = tokens::count_for(model_name, body);
+        let maybe = if body_tokens <= budget {
+            ask(&client, model, body, prompts, generator.max_output_tokens)
+        } else {
+            match long_posts.mode() {
+                LongPostMode::Truncate => ask(
+                    &client,
+                    model,
+                    tokens::truncate(model_name, body, budget),
+                    prompts,
+                    generator.max_output_tokens,
+                ),
+                LongPostMode::MapReduce => map_reduce(
+                    model_name,
+                    post.body,
+                    normalize,
+                    budget,
+                    prompts,
+                    generator.max_output_tokens,
+                    &mut |text, prompts, max_tokens| ask(&client, model, text, prompts, max_tokens),
+                ),
+            }
         };
         let field_value = maybe.context(filepath.display().to_string())?;
+        if body_tokens > budget {
+            too_long.push((filename.to_string(), body_tokens, budget));
+        }
 
-        fm.insert(
-            field_name.to_string(),
-            serde_yaml::Value::String(field_value),
-        );
+        let updated = post.with_field(field_name, &generator.value(field_value))?;
 
-        let y = serde_yaml::to_string(&fm)?;
         let mut writer: Box<dyn io::Write> = if is_backup {
             let mut bak = filepath.clone();
             bak.set_extension("BAK");
@@ -79,15 +219,269 @@ pub fn run(
         } else {
             Box::new(File::create(&filepath)?)
         };
-        writeln!(writer, "---")?;
-        write!(writer, "{y}")?;
-        writeln!(writer, "---")?;
-        write!(writer, "{body}")?;
+        writer.write_all(updated.as_bytes())?;
+        generated::record(&db_conn, &filename, field_name)?;
 
         written_count += 1;
         println!("Processed: {}", filepath.display());
     }
 
     println!("\nUpdated {written_count} posts");
+    if !too_long.is_empty() {
+        let how = match long_posts.mode() {
+            LongPostMode::Truncate => "only the start was sent",
+            LongPostMode::MapReduce => "each part was summarized first",
+        };
+        eprintln!(
+            "\nWarning: {} posts were too long for {model_name}, so {how}:",
+            too_long.len()
+        );
+        for (filename, body_tokens, budget) in too_long {
+            eprintln!("  {filename} ({body_tokens} tokens, {budget} fit)");
+        }
+    }
     Ok(())
 }
+
+fn ask(
+    client: &api::Client,
+    model: super::ModelChoice,
+    text: &str,
+    prompts: super::Prompts,
+    // Longest answer, in tokens
+    max_tokens: Option<usize>,
+) -> anyhow::Result<String> {
+    use super::ModelChoice::*;
+    match model {
+        Gpt4o | Gpt4oMini => openai::message(client, model.name(), text, prompts, max_tokens),
+        Claude35Sonnet | Claude3Haiku => {
+            claude::message(client, model.name(), text, prompts, max_tokens)
+        }
+    }
+}
+
+// Sends text, prompts and the longest answer in tokens to the model
+type Ask<'a> = dyn FnMut(&str, super::Prompts, Option<usize>) -> anyhow::Result<String> + 'a;
+
+// Summarize each part of a post that is too long to send whole, then run
+// `prompts` on those summaries
+fn map_reduce(
+    model_name: &str,
+    // The post's Markdown, so it splits on headings and paragraphs
+    body: &str,
+    normalize: &normalize::Settings,
+    budget: usize,
+    prompts: super::Prompts,
+    max_tokens: Option<usize>,
+    ask: &mut Ask,
+) -> anyhow::Result<String> {
+    let mut notes = summarize_parts(model_name, body, normalize, budget, ask)?;
+    // A very long post with a small budget has too many notes to send at
+    // once, so summarize those in parts too until they fit
+    let mut round = 1;
+    while tokens::count_for(model_name, &notes) > budget {
+        round += 1;
+        let shorter = summarize_parts(model_name, &notes, normalize, budget, ask)
+            .with_context(|| format!("Summarizing the summaries, round {round}"))?;
+        let (was, now) = (
+            tokens::count_for(model_name, &notes),
+            tokens::count_for(model_name, &shorter),
+        );
+        if now >= was {
+            anyhow::bail!(
+                "Summaries of the parts are {now} tokens, more than the {budget} token budget, \
+                and summarizing them again doesn't make them shorter. Raise --max-input-tokens."
+            );
+        }
+        notes = shorter;
+    }
+    ask(&notes, prompts, max_tokens)
+}
+
+// Split `text` into parts that fit in `budget` and summarize each one
+fn summarize_parts(
+    model_name: &str,
+    text: &str,
+    normalize: &normalize::Settings,
+    budget: usize,
+    ask: &mut Ask,
+) -> anyhow::Result<String> {
+    // The chunker counts with cl100k, which can be fewer tokens than the
+    // model's count, so aim a bit lower
+    let sections = chunk::Settings {
+        max_tokens: Some(budget * 4 / 5),
+        overlap: Some(0),
+    }
+    .split(text, normalize);
+    let mut notes = Vec::with_capacity(sections.len());
+    for (idx, section) in sections.iter().enumerate() {
+        let note = ask(
+            tokens::truncate(model_name, section, budget),
+            SECTION_PROMPTS,
+            None,
+        )
+        .with_context(|| format!("Part {} of {}", idx + 1, sections.len()))?;
+        notes.push(note);
+    }
+    Ok(notes.join("\n\n"))
+}
+
+// Posts that already have the field, as examples for the model, with their
+// file names. Hand-written ones first, then newest first. One more than the
+// generator wants, in case one of them is the post being generated.
+fn examples(
+    db_conn: &rusqlite::Connection,
+    site: &Site,
+    generator: &Generator,
+    normalize: &normalize::Settings,
+    model_name: &str,
+) -> anyhow::Result<Vec<(String, super::Example)>> {
+    if generator.examples == 0 {
+        return Ok(vec![]);
+    }
+    // Choose from the front matter first, as the text and related titles
+    // are only needed for the few we keep.
+    // (was it generated, date, page, front matter, answer)
+    let mut found = Vec::new();
+    for page in site.pages(&content::Filter::default())? {
+        let s = fs::read_to_string(&page.path)?;
+        let post = Post::split(&s).context(page.path.display().to_string())?;
+        let fm: serde_json::Map<String, serde_json::Value> =
+            post.parse().context(page.path.display().to_string())?;
+        if matches!(fm.get("draft"), Some(serde_json::Value::Bool(true))) {
+            continue;
+        }
+        let Some(answer) = fm
+            .get(&generator.field)
+            .map(|v| generator.answer(v))
+            .filter(|a| !a.trim().is_empty())
+        else {
+            continue;
+        };
+        let filename = page.rel_path.to_string_lossy().to_string();
+        let is_generated = generated::generated_at(db_conn, &filename, &generator.field)?.is_some();
+        let date = generator::variables(&fm, "", &[])
+            .remove("date")
+            .unwrap_or_default();
+        found.push((is_generated, date, page, fm, answer));
+    }
+    found.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| b.1.cmp(&a.1)));
+    found.truncate(generator.examples + 1);
+
+    let mut examples = Vec::with_capacity(found.len());
+    for (_, _, page, fm, answer) in found {
+        let s = fs::read_to_string(&page.path)?;
+        let post = Post::split(&s).context(page.path.display().to_string())?;
+        let filename = page.rel_path.to_string_lossy().to_string();
+        let vars = generator::variables(
+            &fm,
+            &site.section(&page),
+            &related_titles(db_conn, &filename)?,
+        );
+        let (_, user) = generator.render(&vars);
+        let text = normalize.text(post.body);
+        let example = super::Example {
+            user,
+            text: tokens::truncate(model_name, &text, EXAMPLE_TOKENS).to_string(),
+            answer,
+        };
+        examples.push((filename, example));
+    }
+    Ok(examples)
+}
+
+// Titles of the posts most similar to the one at `filename`, by the last
+// `similar calc`. None if it hasn't been gathered and calculated.
+fn related_titles(db_conn: &rusqlite::Connection, filename: &str) -> anyhow::Result<Vec<String>> {
+    let strategy =
+        db::get_meta(db_conn, similar::LAST_STRATEGY)?.unwrap_or_else(|| Scoring::default().key());
+    let mut stmt = db_conn.prepare_cached(
+        r#"
+        SELECT a.title
+        FROM article_similarity s, article a, article me
+        WHERE me.filename = ?1 AND NOT a.is_draft AND s.strategy = ?2
+         AND ((s.article_a = me.id AND s.article_b = a.id) OR (s.article_a = a.id AND s.article_b = me.id))
+        ORDER BY s.similarity DESC
+        LIMIT ?3"#,
+    )?;
+    let titles = stmt
+        .query_map((filename, &strategy, RELATED_TITLES), |row| row.get(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+    Ok(titles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODEL: &str = "gpt-4o";
+
+    const TAGLINE: crate::Prompts = crate::Prompts {
+        system: "",
+        user: "Write a tagline",
+        examples: &[],
+    };
+
+    // A post of `n` paragraphs of 60 words
+    fn long_post(n: usize) -> String {
+        (0..n)
+            .map(|p| {
+                (0..60)
+                    .map(|w| format!("p{p}w{w}"))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+
+    #[test]
+    fn notes_are_reduced_until_they_fit() {
+        let budget = 200;
+        let mut sizes = Vec::new();
+        let mut tagline_input = String::new();
+        let tagline = map_reduce(
+            MODEL,
+            &long_post(40),
+            &normalize::Settings::default(),
+            budget,
+            TAGLINE,
+            Some(20),
+            &mut |text, _, max_tokens| {
+                sizes.push(tokens::count_for(MODEL, text));
+                if max_tokens == Some(20) {
+                    tagline_input = text.to_string();
+                    return Ok("Tagline".to_string());
+                }
+                // The first ten words of each part
+                let words: Vec<&str> = text.split_whitespace().take(10).collect();
+                Ok(words.join(" "))
+            },
+        )
+        .unwrap();
+        assert_eq!(tagline, "Tagline");
+        assert!(sizes.iter().all(|s| *s <= budget), "{sizes:?}");
+        // 40 parts, then their notes in a few groups, then the tagline
+        assert!(sizes.len() > 42, "{}", sizes.len());
+        // The start of the post made it through both rounds
+        assert!(tagline_input.starts_with("p0w0"));
+    }
+
+    #[test]
+    fn notes_that_dont_shrink_are_an_error() {
+        let err = map_reduce(
+            MODEL,
+            &long_post(10),
+            &normalize::Settings::default(),
+            50,
+            TAGLINE,
+            None,
+            &mut |text, _, _| Ok(text.to_string()),
+        )
+        .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("doesn't make them shorter"),
+            "{err:#}"
+        );
+    }
+}
@@ -1,6 +1,7 @@
 // MIT License
 // Copyright (c) 2024 Graham King
 
+use std::ops::Range;
 use std::path;
 
 use crate::article::Article;
@@ -16,9 +17,12 @@ pub struct FrontMatter {
     pub author: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub r#type: Option<String>,
+    #[serde(deserialize_with = "deserialize_date")]
     pub date: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slug: Option<String>,
     #[serde(default)]
     pub tags: Vec<String>,
     #[serde(
@@ -46,30 +50,728 @@ impl From<FrontMatter> for Article {
             id: 0, // we don't know yet
             title: fm.title,
             url: fm.url.unwrap_or_default(),
-            date: chrono::DateTime::parse_from_rfc3339(&fm.date).ok(),
+            date: parse_date(&fm.date),
             filename: path::PathBuf::new(),
+            section: String::new(),
+            lang: String::new(),
+            page_ref: String::new(),
             is_draft: fm.draft,
+            tags: fm.tags,
+            params: serde_json::Map::new(),
             chunks: vec![],
         }
     }
 }
 
+/// Hugo accepts a full RFC 3339 timestamp, or a date and time without the zone,
+/// or just a date. Times without a zone are treated as UTC.
+pub fn parse_date(s: &str) -> Option<chrono::DateTime<chrono::FixedOffset>> {
+    if let Ok(d) = chrono::DateTime::parse_from_rfc3339(s) {
+        return Some(d);
+    }
+    let naive = chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S")
+        .or_else(|_| chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S"))
+        .or_else(|_| {
+            chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d").map(|d| d.and_time(Default::default()))
+        })
+        .ok()?;
+    Some(naive.and_utc().fixed_offset())
+}
+
 impl FrontMatter {
-    /// Takes a blog post and returns only the front matter lines,
-    /// without the separators.
-    pub fn select(s: &str) -> Vec<&str> {
-        let line_iter = s.lines().skip(1); // skip first "---" line
-        let front_matter_vec = line_iter
-            .take_while(|line| !line.starts_with("---"))
-            .collect::<Vec<&str>>();
-        front_matter_vec
-    }
-
-    // Extract the front matter, the part between the dashes
-    // It's valid yaml
-    pub fn extract(s: &str) -> anyhow::Result<(FrontMatter, usize)> {
-        let front_matter_vec = FrontMatter::select(&s);
-        let fm: FrontMatter = serde_yaml::from_str(&front_matter_vec.join("\n"))?;
-        Ok((fm, front_matter_vec.len()))
+    // Extract the front matter, whichever format it is in, and the body that follows it
+    pub fn extract(s: &str) -> anyhow::Result<(FrontMatter, Post<'_>)> {
+        let post = Post::split(s)?;
+        let fm = post.parse()?;
+        Ok((fm, post))
+    }
+}
+
+/// The syntax of a post's front matter. Hugo decides this from the opening delimiter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Between `---` lines
+    Yaml,
+    /// Between `+++` lines
+    Toml,
+    /// A JSON object starting on the first line
+    Json,
+}
+
+/// A blog post split into front matter and body, both borrowed from the original text
+#[derive(Debug)]
+pub struct Post<'a> {
+    pub format: Format,
+    /// The front matter without the `---` or `+++` separators.
+    /// For JSON this includes the braces.
+    pub front_matter: &'a str,
+    /// Everything after the closing separator, exactly as it was
+    pub body: &'a str,
+
+    // The whole post, and where front_matter starts in it
+    source: &'a str,
+    front_matter_start: usize,
+}
+
+impl<'a> Post<'a> {
+    pub fn split(s: &'a str) -> anyhow::Result<Post<'a>> {
+        let first_line = s.lines().next().unwrap_or_default().trim_end();
+        let format = match first_line {
+            "---" => Format::Yaml,
+            "+++" => Format::Toml,
+            l if l.starts_with('{') => Format::Json,
+            _ => anyhow::bail!("No front matter. Expected first line to be '---', '+++' or '{{'"),
+        };
+        if format == Format::Json {
+            return Post::split_json(s);
+        }
+
+        let mut lines = s.split_inclusive('\n');
+        let opening = lines.next().unwrap(); // we checked first_line above
+        let mut pos = opening.len();
+        for line in lines {
+            if line.trim_end() == first_line {
+                return Ok(Post {
+                    format,
+                    front_matter: &s[opening.len()..pos],
+                    body: &s[pos + line.len()..],
+                    source: s,
+                    front_matter_start: opening.len(),
+                });
+            }
+            pos += line.len();
+        }
+        anyhow::bail!("Front matter has no closing '{first_line}'")
+    }
+
+    // JSON front matter has no separator lines, so find the brace that closes the object
+    fn split_json(s: &'a str) -> anyhow::Result<Post<'a>> {
+        let end = json_value_end(s.as_bytes(), 0)?;
+        // The body starts on the line after the closing brace
+        let rest = &s[end..];
+        let body_start = match rest.find('\n') {
+            Some(nl) if rest[..nl].trim().is_empty() => end + nl + 1,
+            _ => end,
+        };
+        Ok(Post {
+            format: Format::Json,
+            front_matter: &s[..end],
+            body: &s[body_start..],
+            source: s,
+            front_matter_start: 0,
+        })
+    }
+
+    /// Deserialize the front matter according to its format
+    pub fn parse<T: serde::de::DeserializeOwned>(&self) -> anyhow::Result<T> {
+        Ok(match self.format {
+            Format::Yaml => serde_yaml::from_str(self.front_matter)?,
+            Format::Toml => toml::from_str(self.front_matter)?,
+            Format::Json => serde_json::from_str(self.front_matter)?,
+        })
+    }
+
+    /// The whole post with front matter field `key` set to `value`.
+    /// Only the lines of that field change. Every other key, comment, quoting
+    /// style and blank line is kept byte-for-byte. A new key goes after the
+    /// existing top-level ones.
+    pub fn with_field(&self, key: &str, value: &serde_json::Value) -> anyhow::Result<String> {
+        let fm = match self.format {
+            Format::Yaml => set_yaml(self.front_matter, key, value)?,
+            Format::Toml => set_toml(self.front_matter, key, value)?,
+            Format::Json => set_json(self.front_matter, key, value)?,
+        };
+        let end = self.front_matter_start + self.front_matter.len();
+        Ok(format!(
+            "{}{fm}{}",
+            &self.source[..self.front_matter_start],
+            &self.source[end..]
+        ))
+    }
+}
+
+fn set_yaml(fm: &str, key: &str, value: &serde_json::Value) -> anyhow::Result<String> {
+    let mut m = serde_yaml::Mapping::new();
+    m.insert(key.into(), serde_yaml::to_value(value)?);
+    let entry = serde_yaml::to_string(&m)?.replace('\n', newline(fm));
+    Ok(match yaml_key_span(fm, key) {
+        Some(span) => replace_range(fm, span, &entry),
+        None => append_entry(fm, &entry),
+    })
+}
+
+// Byte range of a top-level YAML key, including the lines of its value
+fn yaml_key_span(fm: &str, key: &str) -> Option<Range<usize>> {
+    let mut span: Option<Range<usize>> = None;
+    let mut pos = 0;
+    for line in fm.split_inclusive('\n') {
+        let line_end = pos + line.len();
+        match span.as_mut() {
+            None if yaml_line_key(line) == Some(key) => span = Some(pos..line_end),
+            None => {}
+            // Blank lines only belong to the value if more value follows them
+            Some(_) if line.trim().is_empty() => {}
+            Some(s) if line.starts_with([' ', '\t', '-']) => s.end = line_end,
+            Some(_) => break,
+        }
+        pos = line_end;
+    }
+    span
+}
+
+// The key name if this line starts a top-level YAML key
+fn yaml_line_key(line: &str) -> Option<&str> {
+    let (key, rest) = match line.chars().next()? {
+        q @ ('"' | '\'') => {
+            let close = line[1..].find(q)? + 1;
+            (&line[1..close], &line[close + 1..])
+        }
+        ' ' | '\t' | '#' | '-' => return None,
+        _ => {
+            let colon = line
+                .find(": ")
+                .or_else(|| line.trim_end().strip_suffix(':').map(str::len))?;
+            (&line[..colon], &line[colon..])
+        }
+    };
+    rest.starts_with(':').then_some(key)
+}
+
+fn set_toml(fm: &str, key: &str, value: &serde_json::Value) -> anyhow::Result<String> {
+    let v = toml::Value::try_from(value)?;
+    let is_bare = key
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    let entry = if is_bare {
+        format!("{key} = {v}\n")
+    } else {
+        format!("{} = {v}\n", toml::Value::String(key.to_string()))
+    }
+    .replace('\n', newline(fm));
+
+    // Top-level keys are the ones before the first [table] header
+    let b = fm.as_bytes();
+    let mut pos = 0;
+    let mut last_entry_end = None;
+    while pos < b.len() {
+        let line_end = fm[pos..]
+            .find('\n')
+            .map(|nl| pos + nl + 1)
+            .unwrap_or(b.len());
+        let line = fm[pos..line_end].trim();
+        if line.is_empty() || line.starts_with('#') {
+            pos = line_end;
+            continue;
+        }
+        if line.starts_with('[') {
+            // A table header. Put a new key before it.
+            let at = last_entry_end.unwrap_or(0);
+            return Ok(replace_range(fm, at..at, &entry));
+        }
+        let Some(eq) = fm[pos..line_end].find('=') else {
+            anyhow::bail!("Invalid TOML front matter line: {line}");
+        };
+        let entry_end = toml_value_end(b, pos + eq + 1);
+        if toml_key(&fm[pos..pos + eq]) == key {
+            return Ok(replace_range(fm, pos..entry_end, &entry));
+        }
+        last_entry_end = Some(entry_end);
+        pos = entry_end;
+    }
+    Ok(append_entry(fm, &entry))
+}
+
+fn toml_key(s: &str) -> &str {
+    s.trim().trim_matches(|c| c == '"' || c == '\'')
+}
+
+// Index after the newline that ends the TOML value starting at `from`.
+// Arrays, inline tables and multi-line strings can span several lines.
+fn toml_value_end(b: &[u8], from: usize) -> usize {
+    let mut depth = 0;
+    let mut i = from;
+    while i < b.len() {
+        if b[i..].starts_with(b"\"\"\"") || b[i..].starts_with(b"\'\'\'") {
+            let quotes = &b[i..i + 3];
+            i += 3;
+            while i < b.len() && !b[i..].starts_with(quotes) {
+                if b[i] == b'\\' && quotes[0] == b'"' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            i += 3;
+            continue;
+        }
+        match b[i] {
+            q @ (b'"' | b'\'') => {
+                i += 1;
+                while i < b.len() && b[i] != q && b[i] != b'\n' {
+                    if b[i] == b'\\' && q == b'"' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+            }
+            b'#' => {
+                while i + 1 < b.len() && b[i + 1] != b'\n' {
+                    i += 1;
+                }
+            }
+            b'[' | b'{' => depth += 1,
+            b']' | b'}' => depth -= 1,
+            b'\n' if depth == 0 => return i + 1,
+            _ => {}
+        }
+        i += 1;
+    }
+    b.len()
+}
+
+fn set_json(fm: &str, key: &str, value: &serde_json::Value) -> anyhow::Result<String> {
+    let b = fm.as_bytes();
+    let mut members: Vec<(String, Range<usize>)> = Vec::new();
+    let mut i = json_skip_ws(b, 0);
+    if b.get(i) != Some(&b'{') {
+        anyhow::bail!("JSON front matter is not an object");
+    }
+    i = json_skip_ws(b, i + 1);
+    while b.get(i) == Some(&b'"') {
+        let key_end = json_value_end(b, i)?;
+        let k: String = serde_json::from_str(&fm[i..key_end])?;
+        i = json_skip_ws(b, key_end);
+        if b.get(i) != Some(&b':') {
+            anyhow::bail!("Expected ':' after key {k} in JSON front matter");
+        }
+        let value_start = json_skip_ws(b, i + 1);
+        let value_end = json_value_end(b, value_start)?;
+        members.push((k, value_start..value_end));
+        i = json_skip_ws(b, value_end);
+        if b.get(i) == Some(&b',') {
+            i = json_skip_ws(b, i + 1);
+        }
+    }
+
+    // Match the indent of the existing members
+    let indent = match members.first() {
+        Some((_, r)) => {
+            let line_start = fm[..r.start].rfind('\n').map(|nl| nl + 1).unwrap_or(0);
+            let line = &fm[line_start..r.start];
+            let ws = line.len() - line.trim_start().len();
+            if line_start == 0 {
+                "  ".to_string()
+            } else {
+                line[..ws].to_string()
+            }
+        }
+        None => "  ".to_string(),
+    };
+    let nl = newline(fm);
+    // All on one line stays on one line
+    let is_compact = !fm.trim().contains('\n');
+    let rendered = if is_compact {
+        serde_json::to_string(value)?
+    } else {
+        // Nested the same way as the members are
+        let mut out = Vec::new();
+        let formatter = serde_json::ser::PrettyFormatter::with_indent(indent.as_bytes());
+        serde::Serialize::serialize(
+            value,
+            &mut serde_json::Serializer::with_formatter(&mut out, formatter),
+        )?;
+        String::from_utf8(out)?.replace('\n', &format!("{nl}{indent}"))
+    };
+
+    if let Some((_, r)) = members.iter().find(|(k, _)| k == key) {
+        return Ok(replace_range(fm, r.clone(), &rendered));
+    }
+    let key = serde_json::to_string(key)?;
+    if is_compact {
+        let (comma, colon) = if fm.contains("\": ") {
+            (", ", ": ")
+        } else {
+            (",", ":")
+        };
+        return Ok(match members.last() {
+            Some((_, r)) => {
+                replace_range(fm, r.end..r.end, &format!("{comma}{key}{colon}{rendered}"))
+            }
+            None => format!("{{{key}{colon}{rendered}}}"),
+        });
+    }
+    let member = format!("{indent}{key}: {rendered}");
+    Ok(match members.last() {
+        Some((_, r)) => replace_range(fm, r.end..r.end, &format!(",{nl}{member}")),
+        None => format!("{{{nl}{member}{nl}}}"),
+    })
+}
+
+fn json_skip_ws(b: &[u8], mut i: usize) -> usize {
+    while i < b.len() && b[i].is_ascii_whitespace() {
+        i += 1;
+    }
+    i
+}
+
+// Index just after the JSON value starting at `start`
+fn json_value_end(b: &[u8], start: usize) -> anyhow::Result<usize> {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut is_escaped = false;
+    for (i, &c) in b.iter().enumerate().skip(start) {
+        if in_string {
+            match c {
+                _ if is_escaped => is_escaped = false,
+                b'\\' => is_escaped = true,
+                b'"' => {
+                    in_string = false;
+                    if depth == 0 {
+                        return Ok(i + 1);
+                    }
+                }
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            b'"' => in_string = true,
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' if depth == 0 => return Ok(i),
+            b'}' | b']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(i + 1);
+                }
+            }
+            b',' | b' ' | b'\t' | b'\r' | b'\n' if depth == 0 => return Ok(i),
+            _ => {}
+        }
+    }
+    if depth == 0 && !in_string {
+        return Ok(b.len());
+    }
+    anyhow::bail!("Unterminated JSON front matter")
+}
+
+fn replace_range(s: &str, r: Range<usize>, with: &str) -> String {
+    format!("{}{with}{}", &s[..r.start], &s[r.end..])
+}
+
+// Add a new entry after the last non-blank line
+fn append_entry(fm: &str, entry: &str) -> String {
+    let content_end = fm.trim_end().len();
+    if content_end == 0 {
+        return format!("{entry}{fm}");
+    }
+    match fm[content_end..].find('\n') {
+        Some(nl) => replace_range(fm, content_end + nl + 1..content_end + nl + 1, entry),
+        None => format!("{fm}{}{entry}", newline(fm)),
+    }
+}
+
+// Windows line endings if the front matter already has them, so new lines match
+fn newline(fm: &str) -> &'static str {
+    if fm.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    }
+}
+
+// Dates are strings in YAML and JSON, but TOML has a native date type
+fn deserialize_date<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum Date {
+        Text(String),
+        Toml(toml::value::Datetime),
+    }
+    let d: Date = serde::Deserialize::deserialize(deserializer)?;
+    Ok(match d {
+        Date::Text(s) => s,
+        Date::Toml(dt) => dt.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn split_each_format() {
+        let post = Post::split("---\ntitle: Grüße\n---\nBody\n").unwrap();
+        assert_eq!(post.format, Format::Yaml);
+        assert_eq!(post.front_matter, "title: Grüße\n");
+        assert_eq!(post.body, "Body\n");
+
+        let post = Post::split("+++\r\ntitle = \"Café\"\r\n+++\r\n\r\nBody").unwrap();
+        assert_eq!(post.format, Format::Toml);
+        assert_eq!(post.front_matter, "title = \"Café\"\r\n");
+        assert_eq!(post.body, "\r\nBody");
+
+        let post = Post::split("{\n  \"title\": \"a } in {\\\"quotes\\\"\"\n}\nBody").unwrap();
+        assert_eq!(post.format, Format::Json);
+        assert_eq!(
+            post.front_matter,
+            "{\n  \"title\": \"a } in {\\\"quotes\\\"\"\n}"
+        );
+        assert_eq!(post.body, "Body");
+
+        assert!(Post::split("Just text").is_err());
+        assert!(Post::split("---\ntitle: x\n").is_err());
+        assert!(Post::split("{\"title\": \"x\"").is_err());
+    }
+
+    #[test]
+    fn parse_dates() {
+        let (fm, _) =
+            FrontMatter::extract("+++\ntitle = \"T\"\ndate = 2024-03-01T09:30:00+01:00\n+++\n")
+                .unwrap();
+        assert_eq!(fm.date, "2024-03-01T09:30:00+01:00");
+        let (fm, _) =
+            FrontMatter::extract("{\"title\": \"T\", \"date\": \"2024-03-01\"}\n").unwrap();
+        assert_eq!(fm.date, "2024-03-01");
+    }
+
+    // Set `key` to `value` in `post`, and check that the only change is `old`
+    // becoming `new`, and that the result parses back to `value`
+    fn assert_edit(post: &str, key: &str, value: serde_json::Value, old: &str, new: &str) {
+        let edited = Post::split(post).unwrap().with_field(key, &value).unwrap();
+        assert_eq!(
+            post.matches(old).count(),
+            1,
+            "{old:?} must be in the post once"
+        );
+        assert_eq!(edited, post.replacen(old, new, 1));
+        let fm: serde_json::Map<String, serde_json::Value> =
+            Post::split(&edited).unwrap().parse().unwrap();
+        assert_eq!(fm[key], value, "{edited}");
+    }
+
+    const YAML: &str = r#"---
+title: "Über alles"
+tags:
+- rust
+- hugo
+# Leave this comment alone
+'quoted key': 1
+description: >
+  Folded text
+  over two lines
+notes: |
+  Literal text
+
+  with a blank line
+synopsis: old
+
+---
+Body with key: value lines
+"#;
+
+    #[test]
+    fn yaml_replace_block_list() {
+        assert_edit(
+            YAML,
+            "tags",
+            json!(["café"]),
+            "tags:\n- rust\n- hugo\n",
+            "tags:\n- café\n",
+        );
+    }
+
+    #[test]
+    fn yaml_replace_folded_and_literal() {
+        assert_edit(
+            YAML,
+            "description",
+            json!("New"),
+            "description: >\n  Folded text\n  over two lines\n",
+            "description: New\n",
+        );
+        assert_edit(
+            YAML,
+            "notes",
+            json!("New"),
+            "notes: |\n  Literal text\n\n  with a blank line\n",
+            "notes: New\n",
+        );
+    }
+
+    #[test]
+    fn yaml_replace_quoted_key() {
+        assert_edit(
+            YAML,
+            "quoted key",
+            json!(2),
+            "'quoted key': 1\n",
+            "quoted key: 2\n",
+        );
+    }
+
+    #[test]
+    fn yaml_append() {
+        assert_edit(
+            YAML,
+            "related",
+            json!(["/posts/a/", "/posts/b/"]),
+            "synopsis: old\n",
+            "synopsis: old\nrelated:\n- /posts/a/\n- /posts/b/\n",
+        );
+    }
+
+    #[test]
+    fn yaml_crlf() {
+        let post = "---\r\ntitle: A\r\nsynopsis: old\r\n---\r\nBody\r\n";
+        assert_edit(
+            post,
+            "synopsis",
+            json!("new"),
+            "synopsis: old\r\n",
+            "synopsis: new\r\n",
+        );
+        assert_edit(
+            post,
+            "tagline",
+            json!("Zoë"),
+            "synopsis: old\r\n",
+            "synopsis: old\r\ntagline: Zoë\r\n",
+        );
+    }
+
+    #[test]
+    fn yaml_no_trailing_newline() {
+        assert_edit(
+            "---\ntitle: A\n---",
+            "synopsis",
+            json!("S"),
+            "title: A\n",
+            "title: A\nsynopsis: S\n",
+        );
+    }
+
+    const TOML: &str = r#"+++
+title = "Ünïcode"
+tags = [
+  "rust", # a comment with ] in it
+  "hugo",
+]
+synopsis = 'old'
+
+[params]
+author = "me"
++++
+Body
+"#;
+
+    #[test]
+    fn toml_replace_multi_line_array() {
+        assert_edit(
+            TOML,
+            "tags",
+            json!(["a", "b"]),
+            "tags = [\n  \"rust\", # a comment with ] in it\n  \"hugo\",\n]\n",
+            "tags = [\"a\", \"b\"]\n",
+        );
+        assert_edit(
+            TOML,
+            "synopsis",
+            json!("new"),
+            "synopsis = 'old'\n",
+            "synopsis = \"new\"\n",
+        );
+    }
+
+    #[test]
+    fn toml_append_before_table() {
+        assert_edit(
+            TOML,
+            "related",
+            json!(["/a/"]),
+            "synopsis = 'old'\n",
+            "synopsis = 'old'\nrelated = [\"/a/\"]\n",
+        );
+        // Not one of the table's keys
+        let edited = Post::split(TOML)
+            .unwrap()
+            .with_field("author", &json!("you"))
+            .unwrap();
+        let fm: toml::Table = Post::split(&edited).unwrap().parse().unwrap();
+        assert_eq!(fm["author"].as_str(), Some("you"));
+        assert_eq!(fm["params"]["author"].as_str(), Some("me"));
+    }
+
+    #[test]
+    fn toml_crlf_and_no_trailing_newline() {
+        assert_edit(
+            "+++\r\ntitle = \"A\"\r\n+++\r\nBody",
+            "synopsis",
+            json!("S"),
+            "title = \"A\"\r\n",
+            "title = \"A\"\r\nsynopsis = \"S\"\r\n",
+        );
+        assert_edit(
+            "+++\ntitle = \"A\"\n+++",
+            "synopsis",
+            json!("S"),
+            "title = \"A\"\n",
+            "title = \"A\"\nsynopsis = \"S\"\n",
+        );
+    }
+
+    #[test]
+    fn json_compact() {
+        let post = r#"{"title":"A","tags":["x"]}
+Body"#;
+        assert_edit(post, "tags", json!(["y"]), r#"["x"]"#, r#"["y"]"#);
+        assert_edit(
+            post,
+            "synopsis",
+            json!("S"),
+            r#"["x"]}"#,
+            r#"["x"],"synopsis":"S"}"#,
+        );
+        // Nothing after it at all
+        assert_edit(
+            r#"{"title": "A"}"#,
+            "synopsis",
+            json!("S"),
+            r#""A"}"#,
+            r#""A", "synopsis": "S"}"#,
+        );
+    }
+
+    #[test]
+    fn json_pretty() {
+        let post = "{\n    \"title\": \"A\",\n    \"tags\": [\n        \"x\"\n    ]\n}\n\nBody\n";
+        assert_edit(
+            post,
+            "tags",
+            json!(["y"]),
+            "[\n        \"x\"\n    ]",
+            "[\n        \"y\"\n    ]",
+        );
+        assert_edit(
+            post,
+            "synopsis",
+            json!("S"),
+            "    ]\n}",
+            "    ],\n    \"synopsis\": \"S\"\n}",
+        );
+    }
+
+    #[test]
+    fn json_crlf() {
+        let post = "{\r\n  \"title\": \"A\"\r\n}\r\nBody";
+        assert_edit(
+            post,
+            "tags",
+            json!(["a"]),
+            "\"A\"\r\n}",
+            "\"A\",\r\n  \"tags\": [\r\n    \"a\"\r\n  ]\r\n}",
+        );
     }
 }
@@ -0,0 +1,133 @@
+// MIT License
+// Copyright (c) 2024 Graham King
+
+use chrono::{DateTime, TimeDelta, Utc};
+use rusqlite::OptionalExtension;
+
+/// When to replace a front matter field the post already has.
+/// By default we never do, so hand edits are safe.
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct Refresh {
+    /// Replace the field even if the post already has it
+    #[clap(long)]
+    pub refresh: bool,
+
+    /// Replace the field if hugo-ai wrote it longer ago than this,
+    /// e.g. 90d, 2w, 12h, 1d12h
+    #[clap(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub stale_after: Option<TimeDelta>,
+}
+
+impl Refresh {
+    /// Should we write `field` on a post that already has it
+    pub fn should_replace(
+        &self,
+        db_conn: &rusqlite::Connection,
+        filename: &str,
+        field: &str,
+    ) -> anyhow::Result<bool> {
+        if self.refresh {
+            return Ok(true);
+        }
+        let Some(max_age) = self.stale_after else {
+            return Ok(false);
+        };
+        // No record means someone else wrote it, so leave it alone
+        Ok(generated_at(db_conn, filename, field)?.is_some_and(|at| Utc::now() - at > max_age))
+    }
+}
+
+/// Remember that we just wrote `field` on the post at `filename`
+pub fn record(db_conn: &rusqlite::Connection, filename: &str, field: &str) -> anyhow::Result<()> {
+    db_conn.execute(
+        r#"INSERT INTO generated (filename, field, generated_at) VALUES (?1, ?2, ?3)
+        ON CONFLICT DO UPDATE SET generated_at = excluded.generated_at"#,
+        (filename, field, Utc::now().to_rfc3339()),
+    )?;
+    Ok(())
+}
+
+/// When we last wrote `field` on the post at `filename`, if we ever did
+pub fn generated_at(
+    db_conn: &rusqlite::Connection,
+    filename: &str,
+    field: &str,
+) -> anyhow::Result<Option<DateTime<Utc>>> {
+    let at: Option<String> = db_conn
+        .query_row(
+            "SELECT generated_at FROM generated WHERE filename = ?1 AND field = ?2",
+            (filename, field),
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(at
+        .and_then(|at| DateTime::parse_from_rfc3339(&at).ok())
+        .map(|at| at.to_utc()))
+}
+
+// Durations like "90d", "2w", "1d12h", "30m". No months or years, they vary in length.
+fn parse_duration(s: &str) -> Result<TimeDelta, String> {
+    let mut total = TimeDelta::zero();
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err("empty duration".to_string());
+    }
+    while !rest.is_empty() {
+        let num_len = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(|| format!("'{s}' needs a unit: s, m, h, d or w"))?;
+        let n: i64 = rest[..num_len]
+            .parse()
+            .map_err(|_| format!("'{s}' is not a duration, try 30d"))?;
+        let unit = rest[num_len..].chars().next().unwrap_or_default();
+        let delta = match unit {
+            's' => TimeDelta::try_seconds(n),
+            'm' => TimeDelta::try_minutes(n),
+            'h' => TimeDelta::try_hours(n),
+            'd' => TimeDelta::try_days(n),
+            'w' => TimeDelta::try_weeks(n),
+            _ => {
+                return Err(format!(
+                    "Unknown unit '{unit}' in '{s}', use s, m, h, d or w"
+                ))
+            }
+        };
+        total = delta
+            .and_then(|d| total.checked_add(&d))
+            .ok_or_else(|| format!("'{s}' is too long"))?;
+        rest = &rest[num_len + unit.len_utf8()..];
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90d"), Ok(TimeDelta::days(90)));
+        assert_eq!(
+            parse_duration(" 1d12h "),
+            Ok(TimeDelta::days(1) + TimeDelta::hours(12))
+        );
+        assert_eq!(parse_duration("2w30m5s"), Ok(TimeDelta::seconds(1_211_405)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("  ").is_err());
+        assert!(parse_duration("90").unwrap_err().contains("needs a unit"));
+        assert!(parse_duration("3y")
+            .unwrap_err()
+            .contains("Unknown unit 'y'"));
+        assert!(parse_duration("1d5").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("99999999999999w")
+            .unwrap_err()
+            .contains("too long"));
+        assert!(parse_duration("99999999999999999999d").is_err());
+        // Each part fits, the sum doesn't
+        let weeks = i64::MAX / 1000 / 604_800;
+        assert!(parse_duration(&format!("{weeks}w{weeks}w"))
+            .unwrap_err()
+            .contains("too long"));
+    }
+}
@@ -0,0 +1,274 @@
+// MIT License
+// Copyright (c) 2024 Graham King
+
+use std::collections::HashMap;
+
+use crate::front_matter;
+use crate::ModelChoice;
+
+// Shorter posts aren't worth generating for, unless the generator says otherwise
+const DEFAULT_MIN_LEN: usize = 1000;
+
+// Added to the user prompt of a list generator so the answer is easy to split
+const LIST_PROMPT: &str = "Answer with one item per line and nothing else.";
+
+/// A front-matter field written by a model from the post's text.
+/// `summary` and `tagline` are built in, others are `[generators.<name>]`
+/// in the config file.
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Generator {
+    /// The front-matter field to write. Defaults to the generator's name.
+    pub field: String,
+    pub system: String,
+    pub user: String,
+    /// Used if there's no --model
+    pub model: Option<ModelChoice>,
+    /// Skip posts with less text than this, in bytes
+    pub min_len: Option<usize>,
+    /// Longest answer, in tokens
+    pub max_output_tokens: Option<usize>,
+    pub output: Output,
+    /// How many posts that already have the field to show the model first
+    pub examples: usize,
+}
+
+/// The type of the front-matter field
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Output {
+    #[default]
+    String,
+    /// One item per line of the answer
+    List,
+}
+
+impl Generator {
+    /// The system and user prompts for a post with these `vars`
+    pub fn render(&self, vars: &HashMap<String, String>) -> (String, String) {
+        (render(&self.system, vars), render(&self.user, vars))
+    }
+
+    pub fn min_len(&self) -> usize {
+        self.min_len.unwrap_or(DEFAULT_MIN_LEN)
+    }
+
+    /// The model's `answer` as the value of our field
+    pub fn value(&self, answer: String) -> serde_json::Value {
+        match self.output {
+            Output::String => serde_json::Value::String(answer),
+            Output::List => split_list(&answer).into(),
+        }
+    }
+
+    /// The field's value as we'd like the model to answer, for an example
+    pub fn answer(&self, value: &serde_json::Value) -> String {
+        match self.output {
+            Output::String => text(value, ", "),
+            Output::List => text(value, "\n"),
+        }
+    }
+}
+
+/// What a post's prompts can use as {{name}}: every front-matter field, and
+/// title, date, tags, section, author and related_titles
+pub fn variables(
+    fm: &serde_json::Map<String, serde_json::Value>,
+    section: &str,
+    related_titles: &[String],
+) -> HashMap<String, String> {
+    let mut vars: HashMap<String, String> =
+        fm.iter().map(|(k, v)| (k.clone(), text(v, ", "))).collect();
+    for name in ["title", "date", "tags", "author"] {
+        vars.entry(name.to_string()).or_default();
+    }
+    if let Some(d) = front_matter::parse_date(&vars["date"]) {
+        vars.insert("date".to_string(), d.format("%Y-%m-%d").to_string());
+    }
+    if vars["author"].is_empty() {
+        // The authors taxonomy
+        if let Some(authors) = fm.get("authors") {
+            vars.insert("author".to_string(), text(authors, ", "));
+        }
+    }
+    vars.insert("section".to_string(), section.to_string());
+    vars.insert("related_titles".to_string(), related_titles.join("; "));
+    vars
+}
+
+/// The generator called `name`, from the config file or built in
+pub fn find(configured: &HashMap<String, Generator>, name: &str) -> anyhow::Result<Generator> {
+    let Some(mut generator) = configured.get(name).cloned().or_else(|| builtin(name)) else {
+        let mut names: Vec<&str> = configured.keys().map(|k| k.as_str()).collect();
+        names.extend(["summary", "tagline"]);
+        names.sort();
+        names.dedup();
+        anyhow::bail!(
+            "No generator '{name}'. Add [generators.{name}] to the config file, or use one of: {}",
+            names.join(", ")
+        );
+    };
+    if generator.user.trim().is_empty() {
+        anyhow::bail!("[generators.{name}] needs a user prompt");
+    }
+    if generator.field.is_empty() {
+        generator.field = name.to_string();
+    }
+    if generator.output == Output::List {
+        generator.user = format!("{} {LIST_PROMPT}", generator.user.trim_end());
+    }
+    Ok(generator)
+}
+
+fn builtin(name: &str) -> Option<Generator> {
+    let (field, system, user) = match name {
+        // Hugo already has "summary"
+        "summary" => (
+            "synopsis",
+            "Respond in the first-person as if you are the author. Never refer to the blog post directly.",
+            "Re-write this as a single short concise paragraph, using an active voice. Be direct. Only cover the key points.",
+        ),
+        "tagline" => (
+            "tagline",
+            "Use the past tense",
+            //user: "Write a tagline for this blog post. Try to make it witty, funny, light hearted. Answer with only the tagline. Answer in a single short sentence.",
+            //user: "Write a tagline for this blog post. Answer with only the tagline. Answer in a single short sentence.",
+            "First read this blog post and decide what tone and sentiment it has. Next, write a tagline for it using the same tone and sentiment. For example funny blogs posts should get a light hearted tagline and serious posts should get a direct and informative tagline. Answer only with the tagline. Answer in a single short sentence.",
+        ),
+        _ => return None,
+    };
+    Some(Generator {
+        field: field.to_string(),
+        system: system.to_string(),
+        user: user.to_string(),
+        ..Default::default()
+    })
+}
+
+// `template` with each {{name}} replaced by its value. A name the post doesn't
+// have is replaced by nothing, as not every post has every field. Anything
+// else in braces, such as a shortcode, is left alone.
+fn render(template: &str, vars: &HashMap<String, String>) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        let end = start + 2 + len + 2;
+        let name = rest[start + 2..end - 2].trim();
+        out.push_str(&rest[..start]);
+        if !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+        {
+            out.push_str(vars.get(name).map_or("", |v| v.as_str()));
+        } else {
+            out.push_str(&rest[start..end]);
+        }
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+// A front-matter value as prompt text, with list items joined by `sep`
+fn text(value: &serde_json::Value, sep: &str) -> String {
+    use serde_json::Value;
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| text(item, sep))
+            .filter(|item| !item.is_empty())
+            .collect::<Vec<_>>()
+            .join(sep),
+        // A TOML date is a map with one string in it
+        Value::Object(map) if map.len() == 1 => text(map.values().next().unwrap(), sep),
+        other => other.to_string(),
+    }
+}
+
+// Items one per line, maybe as a Markdown list. A single line is split on
+// commas, which models like to do despite being asked not to.
+fn split_list(answer: &str) -> Vec<String> {
+    let mut items: Vec<String> = answer
+        .lines()
+        .map(|line| {
+            let line = line.trim();
+            let line = line
+                .strip_prefix(['-', '*', '•'])
+                .or_else(|| {
+                    let digits = line.trim_start_matches(|c: char| c.is_ascii_digit());
+                    (digits.len() < line.len())
+                        .then(|| digits.strip_prefix(['.', ')']))
+                        .flatten()
+                })
+                .unwrap_or(line);
+            line.trim().trim_matches('"').to_string()
+        })
+        .filter(|item| !item.is_empty())
+        .collect();
+    if items.len() == 1 && items[0].contains(',') {
+        items = items[0]
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect();
+    }
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_answers() {
+        assert_eq!(
+            split_list("- Rust\n* Ölfarbe\n\n• 日本語\n"),
+            vec!["Rust", "Ölfarbe", "日本語"]
+        );
+        assert_eq!(
+            split_list("1. \"First\"\n2) Second\n10. Tenth"),
+            vec!["First", "Second", "Tenth"]
+        );
+        assert_eq!(
+            split_list("café, naïve , , résumé"),
+            vec!["café", "naïve", "résumé"]
+        );
+        assert_eq!(split_list("2024 in review"), vec!["2024 in review"]);
+        assert!(split_list("\n  \n").is_empty());
+    }
+
+    #[test]
+    fn render_variables() {
+        let fm = serde_json::json!({
+            "title": "Über Äpfel",
+            "date": "2024-03-01T09:30:00+01:00",
+            "tags": ["café", "日本"],
+            "authors": ["Zoë"],
+            "series": "Obst",
+        });
+        let vars = variables(
+            fm.as_object().unwrap(),
+            "posts",
+            &["Birnen".to_string(), "Kirschen".to_string()],
+        );
+        assert_eq!(
+            render(
+                "{{title}} ({{ date }}, {{section}}) by {{author}} in {{series}}: \
+                 {{tags}}. See {{related_titles}}.{{missing}}",
+                &vars
+            ),
+            "Über Äpfel (2024-03-01, posts) by Zoë in Obst: café, 日本. See Birnen; Kirschen."
+        );
+        // Shortcodes and unclosed braces stay as they are
+        assert_eq!(
+            render("{{< figure >}} {{title", &vars),
+            "{{< figure >}} {{title"
+        );
+    }
+}
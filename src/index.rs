@@ -0,0 +1,195 @@
+// MIT License
+// Copyright (c) 2024 Graham King
+
+use std::collections::HashMap;
+
+use crate::article::Article;
+use crate::embedder::Embedder;
+use crate::similar;
+use crate::similar::strategy::{dot, normalize};
+
+// How many of an article's best matching chunks a search returns
+const MATCHES_PER_ARTICLE: usize = 3;
+
+/// Every embedded chunk of every non-draft article, in memory, for searching
+pub struct Index {
+    pub articles: Vec<Article>,
+    // Article id to position in `articles`
+    positions: HashMap<usize, usize>,
+    // Unit length mean of each article's chunks, empty if it has none
+    centroids: Vec<Vec<f64>>,
+    chunks: Vec<Chunk>,
+    /// The model that made the embeddings
+    pub model: String,
+    /// Length of the embeddings
+    pub dim: usize,
+}
+
+struct Chunk {
+    // Position in Index.articles
+    article: usize,
+    chunk_id: usize,
+    text: String,
+    // Unit length
+    embed: Vec<f64>,
+}
+
+/// An article that matches a search
+pub struct Hit<'a> {
+    pub article: &'a Article,
+    /// Its best chunk's score
+    pub score: f64,
+    /// Its best chunks, best first
+    pub matches: Vec<Match<'a>>,
+}
+
+pub struct Match<'a> {
+    pub chunk_id: usize,
+    pub score: f64,
+    pub text: &'a str,
+}
+
+impl Index {
+    pub fn load(db_conn: &rusqlite::Connection) -> anyhow::Result<Index> {
+        let mut stmt = db_conn.prepare(
+            "SELECT DISTINCT embed_model, embed_dim FROM article_chunk WHERE embed IS NOT NULL",
+        )?;
+        let models = stmt
+            .query_map((), |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, usize>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        let (model, dim) = match models.as_slice() {
+            [] => anyhow::bail!("Nothing is embedded yet. Run `hugo-ai similar embed` first."),
+            [one] => one.clone(),
+            _ => anyhow::bail!(
+                "The database has embeddings from more than one model. \
+                Run `hugo-ai similar embed --reembed` with the one you want."
+            ),
+        };
+
+        let articles = similar::load_all_active_articles(db_conn)?;
+        let positions: HashMap<usize, usize> = articles
+            .iter()
+            .enumerate()
+            .map(|(pos, a)| (a.id, pos))
+            .collect();
+        let mut chunks = Vec::new();
+        let mut stmt = db_conn.prepare(
+            r#"SELECT article_id, chunk_id, text, embed FROM article_chunk
+            WHERE embed IS NOT NULL ORDER BY article_id, chunk_id"#,
+        )?;
+        let mut rows = stmt.query(())?;
+        while let Some(row) = rows.next()? {
+            let Some(&article) = positions.get(&row.get::<_, usize>(0)?) else {
+                // Draft
+                continue;
+            };
+            chunks.push(Chunk {
+                article,
+                chunk_id: row.get(1)?,
+                text: row.get(2)?,
+                embed: normalize(similar::u8_vec_to_f64_vec(row.get(3)?)),
+            });
+        }
+        let mut centroids = vec![Vec::new(); articles.len()];
+        for c in chunks.iter() {
+            let sum = &mut centroids[c.article];
+            if sum.is_empty() {
+                *sum = vec![0.0; c.embed.len()];
+            }
+            sum.iter_mut().zip(&c.embed).for_each(|(s, x)| *s += x);
+        }
+        let centroids = centroids.into_iter().map(normalize).collect();
+        Ok(Index {
+            articles,
+            positions,
+            centroids,
+            chunks,
+            model,
+            dim,
+        })
+    }
+
+    pub fn article(&self, id: usize) -> Option<&Article> {
+        self.positions.get(&id).map(|&pos| &self.articles[pos])
+    }
+
+    /// The article's chunks averaged into one unit length vector.
+    /// None if it has no embedded chunks.
+    pub fn centroid(&self, id: usize) -> Option<&[f64]> {
+        self.positions
+            .get(&id)
+            .map(|&pos| self.centroids[pos].as_slice())
+            .filter(|c| !c.is_empty())
+    }
+
+    /// The article at `path`, which can be its filename, page_ref, permalink
+    /// or the path part of its permalink
+    pub fn find(&self, path: &str) -> Option<&Article> {
+        let path = path.trim_end_matches('/');
+        if path.is_empty() {
+            return None;
+        }
+        self.articles.iter().find(|a| {
+            let url = a.url.trim_end_matches('/');
+            a.filename.as_os_str() == path
+                || a.page_ref == path
+                || url == path
+                || url::Url::parse(&a.url).is_ok_and(|u| u.path().trim_end_matches('/') == path)
+        })
+    }
+
+    /// Embed `query` the same way the articles were, so they can be compared
+    pub fn embed_query(&self, embedder: &dyn Embedder, query: &str) -> anyhow::Result<Vec<f64>> {
+        if embedder.model() != self.model {
+            anyhow::bail!(
+                "The articles were embedded with {}, but the query would be embedded with {}. \
+                Use the same provider and model as `similar embed`.",
+                self.model,
+                embedder.model()
+            );
+        }
+        let v = embedder
+            .embed(&[query])?
+            .pop()
+            .ok_or_else(|| anyhow::anyhow!("No embedding returned for the query"))?;
+        if v.len() != self.dim {
+            anyhow::bail!(
+                "The query embedding has {} dimensions but the articles have {}. \
+                Use the same --dimensions as `similar embed`.",
+                v.len(),
+                self.dim
+            );
+        }
+        Ok(normalize(v))
+    }
+
+    /// The `limit` articles with chunks most similar to `query`, best first.
+    /// `query` must be unit length, as from `embed_query`.
+    pub fn search(&self, query: &[f64], limit: usize) -> Vec<Hit<'_>> {
+        let mut by_article: HashMap<usize, Vec<Match>> = HashMap::new();
+        for c in self.chunks.iter() {
+            by_article.entry(c.article).or_default().push(Match {
+                chunk_id: c.chunk_id,
+                score: dot(query, &c.embed),
+                text: &c.text,
+            });
+        }
+        let mut hits: Vec<Hit> = by_article
+            .into_iter()
+            .map(|(pos, mut matches)| {
+                matches.sort_by(|a, b| b.score.total_cmp(&a.score));
+                matches.truncate(MATCHES_PER_ARTICLE);
+                Hit {
+                    article: &self.articles[pos],
+                    score: matches[0].score,
+                    matches,
+                }
+            })
+            .collect();
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+        hits.truncate(limit);
+        hits
+    }
+}
@@ -6,12 +6,28 @@ use std::env;
 use std::fs;
 use std::process;
 
+mod api;
 mod article;
+mod chunk;
 mod claude;
+mod config;
+mod content;
+mod db;
+mod embedder;
+mod export;
 mod field;
 mod front_matter;
+mod generated;
+mod generator;
+mod index;
+mod normalize;
+mod ollama;
 mod openai;
+mod search;
+mod serve;
 mod similar;
+mod site;
+mod tokens;
 
 const DB_NAME: &str = "hugo-ai.db";
 const CFG_DIR: &str = ".config/hugo-ai";
@@ -22,45 +38,105 @@ struct Cli {
     #[arg(long, value_name = "PATH")]
     db_path: Option<String>,
 
+    /// Sets a custom config file path. Defaults to hugo-ai.toml in the current
+    /// directory, then in ~/.config/hugo-ai/
+    #[arg(long, value_name = "PATH")]
+    config: Option<String>,
+
     #[clap(subcommand)]
     command: Commands,
 }
 
 #[derive(Subcommand)]
 enum Commands {
+    /// Look after the sqlite database
+    Db {
+        #[clap(subcommand)]
+        subcommand: db::Commands,
+    },
     Similar {
         #[clap(subcommand)]
         subcommand: similar::Commands,
     },
-    Summary {
-        /// The directory with the markdown files
-        directory: String,
+    /// Write files for the site to use directly
+    Export {
+        #[clap(subcommand)]
+        subcommand: export::Commands,
+    },
+    /// Find the posts closest in meaning to some text. Needs `similar embed` first.
+    Search {
+        /// What to look for
+        query: String,
 
-        /// Do no backup the file as a .BAK
-        #[clap(long)]
-        no_backup: bool,
+        /// How many posts to show
+        #[clap(long, default_value_t = 10)]
+        limit: usize,
 
-        /// Use big model (gpt-4o or claude-3.5-sonnet) or
-        /// small model (gpt-4o-mini or claude-3-haiku)
+        /// Print JSON instead of text
         #[clap(long)]
-        model: ModelChoice,
+        json: bool,
+
+        #[clap(flatten)]
+        settings: embedder::Settings,
     },
-    Tagline {
-        /// The directory with the markdown files
-        directory: String,
+    /// Answer search and related post queries over HTTP, from the database
+    Serve {
+        /// Address and port to listen on
+        #[clap(long, default_value = "127.0.0.1:8080")]
+        listen: String,
 
-        /// Do no backup the file as a .BAK
-        #[clap(long)]
-        no_backup: bool,
+        /// Let pages from this origin call the API, e.g. https://example.com, or * for any
+        #[clap(long, value_name = "ORIGIN")]
+        allow_origin: Option<String>,
 
-        /// Use big model (gpt-4o or claude-3.5-sonnet) or
-        /// small model (gpt-4o-mini or claude-3-haiku)
-        #[clap(long)]
-        model: ModelChoice,
+        #[clap(flatten)]
+        settings: embedder::Settings,
     },
+    /// Write a synopsis of each post, with the summary generator
+    Summary(GenerateArgs),
+    /// Write a tagline for each post, with the tagline generator
+    Tagline(GenerateArgs),
+    /// Write a front-matter field with a generator from the config file,
+    /// or a built in one (summary, tagline)
+    Generate {
+        /// Which [generators.<name>]
+        name: String,
+
+        #[clap(flatten)]
+        args: GenerateArgs,
+    },
+}
+
+/// The posts to run a generator over, and how
+#[derive(clap::Args)]
+struct GenerateArgs {
+    /// The Hugo site root, or a directory of Markdown posts
+    directory: String,
+
+    #[clap(flatten)]
+    filter: content::Filter,
+
+    /// Do no backup the file as a .BAK
+    #[clap(long)]
+    no_backup: bool,
+
+    #[clap(flatten)]
+    refresh: generated::Refresh,
+
+    #[clap(flatten)]
+    normalize: normalize::Settings,
+
+    #[clap(flatten)]
+    long_posts: field::LongPosts,
+
+    /// Overrides the generator's model. Big model (gpt-4o or claude-3.5-sonnet)
+    /// or small model (gpt-4o-mini or claude-3-haiku) [default: gpt4o]
+    #[clap(long)]
+    model: Option<ModelChoice>,
 }
 
-#[derive(Default, Clone, Copy, ValueEnum)]
+#[derive(Default, Clone, Copy, Debug, ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
 enum ModelChoice {
     #[default]
     Gpt4o,
@@ -69,63 +145,106 @@ enum ModelChoice {
     Claude3Haiku,
 }
 
-#[derive(Clone, Copy, Debug)]
-struct Prompts {
-    system: &'static str,
-    user: &'static str,
+impl ModelChoice {
+    /// The provider's name for the model
+    fn name(self) -> &'static str {
+        use ModelChoice::*;
+        match self {
+            Gpt4o => openai::CHAT_MODEL_BIG,
+            Gpt4oMini => openai::CHAT_MODEL_SMALL,
+            Claude35Sonnet => claude::CHAT_MODEL_BIG,
+            Claude3Haiku => claude::CHAT_MODEL_SMALL,
+        }
+    }
 }
 
-const SUMMARIZE_PROMPTS: Prompts = Prompts{
-    system: "Respond in the first-person as if you are the author. Never refer to the blog post directly.",
-    user: "Re-write this as a single short concise paragraph, using an active voice. Be direct. Only cover the key points.",
-};
+#[derive(Clone, Copy, Debug)]
+struct Prompts<'a> {
+    system: &'a str,
+    user: &'a str,
+    // Earlier questions and answers, sent first so the model copies their style
+    examples: &'a [Example],
+}
 
-const TAGLINE_PROMPTS: Prompts = Prompts {
-    system: "Use the past tense",
-    //user: "Write a tagline for this blog post. Try to make it witty, funny, light hearted. Answer with only the tagline. Answer in a single short sentence.",
-    //user: "Write a tagline for this blog post. Answer with only the tagline. Answer in a single short sentence.",
-    user: "First read this blog post and decide what tone and sentiment it has. Next, write a tagline for it using the same tone and sentiment. For example funny blogs posts should get a light hearted tagline and serious posts should get a direct and informative tagline. Answer only with the tagline. Answer in a single short sentence."
-};
+/// A post that already has the field, and the field's value
+#[derive(Clone, Debug)]
+struct Example {
+    // The user prompt for that post
+    user: String,
+    // The post's text
+    text: String,
+    answer: String,
+}
 
 fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    let cfg_dir = env::var("HOME")
+        .map(|user_home| format!("{user_home}/{CFG_DIR}"))
+        .ok();
     let db_path = match cli.db_path {
         Some(db) => db,
         None => {
-            let Ok(user_home) = env::var("HOME") else {
+            let Some(cfg_dir) = cfg_dir.as_ref() else {
                 eprintln!("$HOME not set");
                 process::exit(1);
             };
-            let cfg_dir = format!("{user_home}/{CFG_DIR}");
-            fs::create_dir_all(&cfg_dir)?;
+            fs::create_dir_all(cfg_dir)?;
             format!("{cfg_dir}/{DB_NAME}")
         }
     };
+    let config = config::Config::load(cli.config.as_deref(), cfg_dir.as_deref().unwrap_or(""))?;
     match cli.command {
-        Commands::Similar { subcommand } => similar::run(&db_path, subcommand),
-        Commands::Summary {
-            directory,
-            no_backup,
-            model,
-        } => field::run(
-            &directory,
-            model,
-            !no_backup,
-            "synopsis",
-            SUMMARIZE_PROMPTS,
-            1000,
+        Commands::Db { subcommand } => db::run(&db_path, subcommand),
+        Commands::Similar { subcommand } => similar::run(&db_path, &config, subcommand),
+        Commands::Export { subcommand } => export::run(&db_path, subcommand),
+        Commands::Search {
+            query,
+            limit,
+            json,
+            settings,
+        } => search::run(
+            &db_path,
+            &query,
+            limit,
+            json,
+            settings.or(&config.embed),
+            &config.http,
         ),
-        Commands::Tagline {
-            directory,
-            no_backup,
-            model,
-        } => field::run(
-            &directory,
-            model,
-            !no_backup,
-            "tagline",
-            TAGLINE_PROMPTS,
-            1000,
+        Commands::Serve {
+            listen,
+            allow_origin,
+            settings,
+        } => serve::run(
+            &db_path,
+            &listen,
+            allow_origin.as_deref(),
+            settings.or(&config.embed),
+            &config,
         ),
+        Commands::Summary(args) => generate(&db_path, &config, "summary", args),
+        Commands::Tagline(args) => generate(&db_path, &config, "tagline", args),
+        Commands::Generate { name, args } => generate(&db_path, &config, &name, args),
     }
 }
+
+// Run generator `name` over the posts
+fn generate(
+    db_path: &str,
+    config: &config::Config,
+    name: &str,
+    args: GenerateArgs,
+) -> anyhow::Result<()> {
+    let generator = generator::find(&config.generators, name)?;
+    field::run(
+        db_path,
+        &args.directory,
+        &args.filter,
+        &args.refresh,
+        args.model.or(generator.model).unwrap_or_default(),
+        !args.no_backup,
+        &generator,
+        &args.normalize.or(&config.normalize),
+        &args.long_posts.or(&config.long_posts),
+        &config.http,
+    )
+}
@@ -0,0 +1,538 @@
+// MIT License
+// Copyright (c) 2024 Graham King
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use pulldown_cmark::{Event, Options, Parser, Tag, TagEnd};
+
+/// How to clean up a post before embedding it or sending it to a model:
+/// shortcodes, HTML, link targets and footnote markers go, the words stay.
+/// The `[normalize]` section of the config file, with command line flags
+/// taking priority.
+#[derive(clap::Args, serde::Deserialize, Clone, Debug, Default)]
+#[group(id = "normalize")]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Leave code blocks out of the text
+    #[clap(long, num_args = 0..=1, default_missing_value = "true")]
+    pub drop_code: Option<bool>,
+
+    /// What to do with each shortcode, by name, e.g. `notice = "inner"`.
+    /// Added to and overriding the built in rules.
+    #[clap(skip)]
+    pub shortcodes: HashMap<String, Rule>,
+}
+
+/// What a shortcode becomes
+#[derive(serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Rule {
+    /// Nothing, including anything between its opening and closing tags
+    Drop,
+    /// Whatever is between its opening and closing tags
+    Inner,
+    /// The value of this parameter, e.g. `{ keep = "caption" }`
+    Keep(String),
+}
+
+/// A top level piece of a post, as plain text
+pub struct Block {
+    pub text: String,
+    pub is_heading: bool,
+    /// A code block, table or HTML, which only makes sense whole
+    pub is_whole: bool,
+}
+
+impl Settings {
+    /// Fill in anything not set here from `other`
+    pub fn or(self, other: &Settings) -> Settings {
+        let mut shortcodes = other.shortcodes.clone();
+        shortcodes.extend(self.shortcodes);
+        Settings {
+            drop_code: self.drop_code.or(other.drop_code),
+            shortcodes,
+        }
+    }
+
+    pub fn drop_code(&self) -> bool {
+        self.drop_code.unwrap_or(false)
+    }
+
+    /// The Markdown `body` as plain text, one paragraph per line
+    pub fn text(&self, body: &str) -> String {
+        self.blocks(body)
+            .into_iter()
+            .map(|b| b.text)
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+
+    /// The Markdown `body` as plain text, split into its top level blocks:
+    /// paragraphs, headings, lists, code blocks, ... Empty ones are left out.
+    pub fn blocks(&self, body: &str) -> Vec<Block> {
+        let expanded = self.expand_shortcodes(body);
+        let mut out = Vec::new();
+        let mut current = Block {
+            text: String::new(),
+            is_heading: false,
+            is_whole: false,
+        };
+        let mut depth = 0;
+        let mut is_code = false;
+        for event in Parser::new_ext(&expanded, options()) {
+            match event {
+                Event::Start(tag) => {
+                    if depth == 0 {
+                        current.is_heading = matches!(tag, Tag::Heading { .. });
+                        current.is_whole =
+                            matches!(tag, Tag::CodeBlock(_) | Tag::Table(_) | Tag::HtmlBlock);
+                    }
+                    is_code |= matches!(tag, Tag::CodeBlock(_));
+                    depth += 1;
+                }
+                Event::End(tag) => {
+                    depth -= 1;
+                    match tag {
+                        TagEnd::CodeBlock => is_code = false,
+                        // Keep nested blocks apart
+                        TagEnd::Paragraph
+                        | TagEnd::Item
+                        | TagEnd::TableHead
+                        | TagEnd::TableRow
+                        | TagEnd::Heading(_) => current.text.push('\n'),
+                        TagEnd::TableCell => current.text.push_str(" | "),
+                        _ => {}
+                    }
+                }
+                Event::Text(_) if is_code && self.drop_code() => {}
+                // Link and image tags are skipped, leaving their text
+                Event::Text(t) | Event::Code(t) => current.text.push_str(&t),
+                Event::InlineMath(t) | Event::DisplayMath(t) => current.text.push_str(&t),
+                Event::Html(t) | Event::InlineHtml(t) => current.text.push_str(&strip_html(&t)),
+                Event::SoftBreak | Event::HardBreak => current.text.push('\n'),
+                Event::FootnoteReference(_) | Event::TaskListMarker(_) | Event::Rule => {}
+            }
+            if depth == 0 {
+                let text = current.text.trim();
+                if !text.is_empty() {
+                    out.push(Block {
+                        text: text.to_string(),
+                        is_heading: current.is_heading,
+                        is_whole: current.is_whole,
+                    });
+                }
+                current.text.clear();
+            }
+        }
+        out
+    }
+
+    fn rule(&self, name: &str, is_paired: bool) -> Rule {
+        if let Some(rule) = self.shortcodes.get(name) {
+            return rule.clone();
+        }
+        match name {
+            "figure" => Rule::Keep("caption".to_string()),
+            "highlight" if self.drop_code() => Rule::Drop,
+            // Embeds and links have no words worth keeping
+            "youtube" | "vimeo" | "tweet" | "x" | "instagram" | "gist" | "param" | "ref"
+            | "relref" => Rule::Drop,
+            _ if is_paired => Rule::Inner,
+            _ => Rule::Drop,
+        }
+    }
+
+    // Replace every shortcode in `s` following its rule. Those in code spans
+    // and blocks are left alone, as they are examples in posts about Hugo.
+    fn expand_shortcodes(&self, s: &str) -> String {
+        let code = code_ranges(s);
+        let found = shortcodes(s, &code);
+        let mut out = String::with_capacity(s.len());
+        self.expand(s, &found, 0..found.len(), 0..s.len(), &mut out);
+        out
+    }
+
+    // Expand `range` of `s`, which holds the shortcodes `tags` of `found`, onto `out`
+    fn expand(
+        &self,
+        s: &str,
+        found: &[Found],
+        tags: Range<usize>,
+        range: Range<usize>,
+        out: &mut String,
+    ) {
+        let mut pos = range.start;
+        let mut idx = tags.start;
+        while idx < tags.end {
+            let f = &found[idx];
+            out.push_str(&s[pos..f.start]);
+            // Skip to after the closing tag, if there is one
+            let next = f.close.map_or(idx, |c| c) + 1;
+            pos = found[next - 1].end;
+            if let Some(literal) = f.tag.literal.as_ref() {
+                out.push_str(literal);
+            } else if f.tag.is_close {
+                // Stray closing tag
+            } else {
+                match self.rule(f.tag.name, f.close.is_some()) {
+                    Rule::Drop => {}
+                    Rule::Inner => {
+                        if let Some(c) = f.close {
+                            self.expand(s, found, idx + 1..c, f.end..found[c].start, out);
+                        }
+                    }
+                    Rule::Keep(param) => out.push_str(f.tag.param(&param).unwrap_or_default()),
+                }
+            }
+            idx = next;
+        }
+        out.push_str(&s[pos..range.end]);
+    }
+}
+
+fn options() -> Options {
+    Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+}
+
+// Where the code spans and code blocks are in the Markdown `s`, in order
+fn code_ranges(s: &str) -> Vec<Range<usize>> {
+    Parser::new_ext(s, options())
+        .into_offset_iter()
+        .filter_map(|(event, range)| match event {
+            Event::Start(Tag::CodeBlock(_)) | Event::Code(_) => Some(range),
+            _ => None,
+        })
+        .collect()
+}
+
+// `{{< name params >}}` or `{{% name params %}}`
+struct Shortcode<'a> {
+    name: &'a str,
+    // Name, or None for positional parameters
+    params: Vec<(Option<&'a str>, &'a str)>,
+    is_close: bool,
+    is_self_closing: bool,
+    // `{{</* name */>}}` is the way to write `{{< name >}}` as text
+    literal: Option<String>,
+}
+
+impl<'a> Shortcode<'a> {
+    fn param(&self, name: &str) -> Option<&'a str> {
+        self.params
+            .iter()
+            .find(|(k, _)| *k == Some(name))
+            .map(|(_, v)| *v)
+    }
+}
+
+// A shortcode in the text
+struct Found<'a> {
+    start: usize,
+    end: usize,
+    tag: Shortcode<'a>,
+    // Index of its closing tag, for an opening tag that has one
+    close: Option<usize>,
+}
+
+// Every shortcode in `s` that isn't in `code`, in order. Opening tags are
+// paired with their closing tags in one pass, innermost first, so a pair
+// never straddles another one.
+fn shortcodes<'a>(s: &'a str, code: &[Range<usize>]) -> Vec<Found<'a>> {
+    let mut found: Vec<Found> = Vec::new();
+    // Opening tags waiting for their closing tag, and how many of each name
+    let mut open: Vec<usize> = Vec::new();
+    let mut open_names: HashMap<&str, usize> = HashMap::new();
+    let mut pos = 0;
+    while let Some((start, end, tag)) = next_shortcode(s, code, pos..s.len()) {
+        pos = end;
+        let idx = found.len();
+        if !tag.is_self_closing && !tag.is_close {
+            open.push(idx);
+            *open_names.entry(tag.name).or_default() += 1;
+        } else if tag.is_close && open_names.get(tag.name).is_some_and(|n| *n > 0) {
+            // Tags opened since this one's opener were never closed
+            while let Some(o) = open.pop() {
+                let name = found[o].tag.name;
+                if let Some(n) = open_names.get_mut(name) {
+                    *n -= 1;
+                }
+                if name == tag.name {
+                    found[o].close = Some(idx);
+                    break;
+                }
+            }
+        }
+        found.push(Found {
+            start,
+            end,
+            tag,
+            close: None,
+        });
+    }
+    found
+}
+
+// The next shortcode in `range` of `s` that isn't in `code`: where it starts,
+// where it ends, and what it is
+fn next_shortcode<'a>(
+    s: &'a str,
+    code: &[Range<usize>],
+    range: Range<usize>,
+) -> Option<(usize, usize, Shortcode<'a>)> {
+    let mut from = range.start;
+    let start = loop {
+        let rest = &s[from..range.end];
+        let at = rest.find("{{")?;
+        if !matches!(rest.as_bytes().get(at + 2), Some(b'<' | b'%')) {
+            from += at + 1;
+            continue;
+        }
+        let start = from + at;
+        let idx = code.partition_point(|c| c.end <= start);
+        match code.get(idx) {
+            Some(c) if c.start <= start => from = c.end.min(range.end),
+            _ => break start,
+        }
+    };
+    let (open, close) = if s[start..].starts_with("{{<") {
+        ("{{<", ">}}")
+    } else {
+        ("{{%", "%}}")
+    };
+    let inner_start = start + open.len();
+    let end = inner_start + s[inner_start..range.end].find(close)? + close.len();
+    let mut inner = s[inner_start..end - close.len()].trim();
+
+    if let Some(text) = inner.strip_prefix("/*").and_then(|i| i.strip_suffix("*/")) {
+        let tag = Shortcode {
+            name: "",
+            params: Vec::new(),
+            is_close: false,
+            is_self_closing: true,
+            literal: Some(format!("{open} {} {close}", text.trim())),
+        };
+        return Some((start, end, tag));
+    }
+    let is_close = inner.starts_with('/');
+    let is_self_closing = inner.ends_with('/');
+    inner = inner.trim_start_matches('/').trim_end_matches('/').trim();
+    let (name, params) = inner.split_once(char::is_whitespace).unwrap_or((inner, ""));
+    let tag = Shortcode {
+        name,
+        params: parse_params(params),
+        is_close,
+        is_self_closing,
+        literal: None,
+    };
+    Some((start, end, tag))
+}
+
+// `caption="A dog" src=dog.jpg 42` -> named and positional values, unquoted
+fn parse_params(mut s: &str) -> Vec<(Option<&str>, &str)> {
+    let mut params = Vec::new();
+    loop {
+        s = s.trim_start();
+        if s.is_empty() {
+            return params;
+        }
+        let (first, rest) = take_value(s);
+        match rest.strip_prefix('=') {
+            Some(rest) => {
+                let (value, rest) = take_value(rest.trim_start());
+                params.push((Some(first), value));
+                s = rest;
+            }
+            None => {
+                params.push((None, first));
+                s = rest;
+            }
+        }
+    }
+}
+
+// A quoted or bare value from the start of `s`, and what comes after it
+fn take_value(s: &str) -> (&str, &str) {
+    for quote in ['"', '`'] {
+        if let Some(rest) = s.strip_prefix(quote) {
+            let mut prev = ' ';
+            for (idx, c) in rest.char_indices() {
+                if c == quote && (quote == '`' || prev != '\\') {
+                    return (&rest[..idx], &rest[idx + 1..]);
+                }
+                prev = c;
+            }
+            // Unterminated
+            return (rest, "");
+        }
+    }
+    let end = s
+        .find(|c: char| c.is_whitespace() || c == '=')
+        .unwrap_or(s.len());
+    (&s[..end], &s[end..])
+}
+
+// Text of some HTML: tags, comments, scripts and styles go
+fn strip_html(s: &str) -> String {
+    // ASCII lowercasing keeps byte offsets the same
+    let lower = s.to_ascii_lowercase();
+    let mut out = String::with_capacity(s.len());
+    let mut pos = 0;
+    while let Some(start) = lower[pos..].find('<').map(|i| pos + i) {
+        out.push_str(&s[pos..start]);
+        let tag = &lower[start..];
+        let skip_to = if tag.starts_with("<!--") {
+            "-->"
+        } else if tag.starts_with("<script") {
+            "</script>"
+        } else if tag.starts_with("<style") {
+            "</style>"
+        } else {
+            ">"
+        };
+        match tag.find(skip_to) {
+            Some(end) => pos = start + end + skip_to.len(),
+            // No more tags can close, so the rest is text with less-than signs
+            None if skip_to == ">" => {
+                pos = start;
+                break;
+            }
+            None => pos = s.len(),
+        }
+    }
+    out.push_str(&s[pos..]);
+    for (entity, c) in [
+        ("&nbsp;", " "),
+        ("&lt;", "<"),
+        ("&gt;", ">"),
+        ("&quot;", "\""),
+        ("&#39;", "'"),
+        ("&amp;", "&"),
+    ] {
+        out = out.replace(entity, c);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(body: &str) -> String {
+        Settings::default().text(body)
+    }
+
+    #[test]
+    fn figure_keeps_its_caption() {
+        assert_eq!(
+            text(r#"Look: {{< figure src="/café.jpg" caption="Un café à Paris" >}} nice."#),
+            "Look: Un café à Paris nice."
+        );
+        assert_eq!(text("{{< figure src=/a.jpg />}}"), "");
+    }
+
+    #[test]
+    fn shortcode_rules() {
+        let body = "{{< youtube abc123 >}}\n\n{{% notice tip %}}Keep **this**{{% /notice %}}\n\n\
+            A{{< ref \"other.md\" >}}B{{</* figure src=x */>}}C";
+        assert_eq!(text(body), "Keep this\n\nAB{{< figure src=x >}}C");
+
+        let mut settings = Settings::default();
+        settings.shortcodes.insert("notice".to_string(), Rule::Drop);
+        settings
+            .shortcodes
+            .insert("quote".to_string(), Rule::Keep("author".to_string()));
+        let body = "{{% notice %}}gone{{% /notice %}}{{< quote author=\"Zoë\" >}}";
+        assert_eq!(settings.text(body), "Zoë");
+    }
+
+    #[test]
+    fn shortcodes_in_code_stay() {
+        let body = "Write `{{< youtube id >}}` to embed.\n\n\
+            ```\n{{< figure src=a.jpg >}}\n{{% notice %}}\n```\n\n\
+            {{% notice %}}Après{{% /notice %}}\n\n    {{< gist x >}}\n\n\
+            {{%/* notice */%}} and {{</* /notice */>}}";
+        assert_eq!(
+            text(body),
+            "Write {{< youtube id >}} to embed.\n\n\
+             {{< figure src=a.jpg >}}\n{{% notice %}}\n\n\
+             Après\n\n{{< gist x >}}\n\n\
+             {{% notice %}} and {{< /notice >}}"
+        );
+        // A pair around a code block
+        let body = "{{< youtube >}}\n\n```\ncode\n```\n\n{{< /youtube >}}Après";
+        assert_eq!(text(body), "Après");
+    }
+
+    #[test]
+    fn nested_shortcodes() {
+        let body = "{{< box >}}out {{< box >}}in{{< /box >}} out{{< /box >}}";
+        assert_eq!(text(body), "out in out");
+    }
+
+    #[test]
+    fn unpaired_shortcodes() {
+        // The first box is never closed, and the note's closing tag is outside
+        // its parent, so both are dropped
+        let body = "{{< box >}}a{{< box >}}b{{< note >}}c{{< /box >}}d{{< /note >}}";
+        assert_eq!(text(body), "abcd");
+        // Many unclosed tags take one pass, not one scan each
+        let body = "{{< box >}}x".repeat(20_000);
+        assert_eq!(text(&body), "x".repeat(20_000));
+    }
+
+    #[test]
+    fn markup_becomes_text() {
+        let body = "Read [the docs](https://example.com) and ![a cat](cat.png).[^1]\n\n\
+            <div class=\"note\">Tom &amp; Jerry<script>alert(1)</script></div>\n\n\
+            [^1]: Footnote.";
+        assert_eq!(
+            text(body),
+            "Read the docs and a cat.\n\nTom & Jerry\n\nFootnote."
+        );
+    }
+
+    #[test]
+    fn html_tags_go() {
+        assert_eq!(
+            strip_html("a<B>b</B><SCRIPT>x</Script>c<!-- <p> -->d<STYLE>p{}</style>e"),
+            "abcde"
+        );
+        assert_eq!(strip_html("1 &lt; 2 <p>ü</p> 3 < 4"), "1 < 2 ü 3 < 4");
+        assert_eq!(strip_html("x <script>never closed"), "x ");
+        // Not quadratic
+        let many = "a < b ".repeat(200_000);
+        assert_eq!(strip_html(&many), many);
+    }
+
+    #[test]
+    fn drop_code() {
+        let body = "Before\n\n```\nlet x = 1;\n```\n\n{{< highlight go >}}x := 1{{< /highlight >}}\n\nAfter `inline`";
+        assert_eq!(text(body), "Before\n\nlet x = 1;\n\nx := 1\n\nAfter inline");
+        let settings = Settings {
+            drop_code: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(settings.text(body), "Before\n\nAfter inline");
+    }
+
+    #[test]
+    fn headings_and_code_are_marked() {
+        let blocks = Settings::default().blocks("# Título\n\nTexto\n\n    code\n");
+        let kinds: Vec<(&str, bool, bool)> = blocks
+            .iter()
+            .map(|b| (b.text.as_str(), b.is_heading, b.is_whole))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("Título", true, false),
+                ("Texto", false, false),
+                ("code", false, true)
+            ]
+        );
+    }
+}
@@ -0,0 +1,51 @@
+// MIT License
+// Copyright (c) 2024 Graham King
+
+use crate::api;
+
+pub const API_BASE: &str = "http://localhost:11434";
+pub const EMBED_MODEL: &str = "nomic-embed-text";
+
+#[derive(Debug, serde::Serialize)]
+struct EmbedRequest<'a> {
+    model: &'a str,
+    input: &'a [&'a str],
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct EmbedResponse {
+    embeddings: Vec<Vec<f64>>,
+}
+
+/// Calculate an embedding for each of these strings on a local Ollama server,
+/// in one request. The model must already be pulled.
+pub fn embed(
+    client: &api::Client,
+    base_url: &str,
+    model: &str,
+    input: &[&str],
+) -> anyhow::Result<Vec<Vec<f64>>> {
+    let req = EmbedRequest { model, input };
+    let req_builder = client
+        .post(&format!("{}/api/embed", base_url.trim_end_matches('/')))
+        .json(&req);
+    let out: EmbedResponse = client.send_json(req_builder)?;
+    if out.embeddings.len() != input.len() {
+        return Err(anyhow::anyhow!(
+            "Sent {} inputs but got {} embeddings",
+            input.len(),
+            out.embeddings.len()
+        ));
+    }
+    Ok(out.embeddings)
+
+    /* Example response
+    {
+      "model": "nomic-embed-text",
+      "embeddings": [[0.010071029, -0.0017594862, 0.05007221, ...]],
+      "total_duration": 14143917,
+      "load_duration": 1019500,
+      "prompt_eval_count": 8
+    }
+    */
+}
@@ -1,13 +1,22 @@
 // MIT License
 // Copyright (c) 2024 Graham King
 
+use crate::api;
+
 pub const CHAT_MODEL_BIG: &str = "gpt-4o";
 pub const CHAT_MODEL_SMALL: &str = "gpt-4o-mini";
 
+/// Where the real OpenAI API lives. Compatible servers have their own.
+pub const API_BASE: &str = "https://api.openai.com/v1";
+pub const EMBED_MODEL: &str = "text-embedding-3-small";
+
 #[derive(Debug, serde::Serialize)]
 struct EmbedRequest<'a> {
-    model: &'static str,
-    input: &'a str,
+    model: &'a str,
+    input: &'a [&'a str],
+    // Only the text-embedding-3 models accept this
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dimensions: Option<usize>,
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -17,29 +26,42 @@ struct EmbedResponse {
 
 #[derive(Debug, serde::Deserialize)]
 struct Embedding {
+    index: usize,
     embedding: Vec<f64>,
 }
 
-/// Use model text-embedding-3-small to calculate an embedding for this string
-pub fn embed(body: &str) -> anyhow::Result<Vec<f64>> {
-    let Ok(api_key) = std::env::var("OPENAI_API_KEY") else {
-        return Err(anyhow::anyhow!("Set variable OPENAI_API_KEY to your key"));
-    };
+/// Calculate an embedding for each of these strings, in one request.
+/// Works with OpenAI and with servers that copy its API (llama.cpp server,
+/// LM Studio, vLLM), which may not need a key.
+pub fn embed(
+    client: &api::Client,
+    base_url: &str,
+    api_key: Option<&str>,
+    model: &str,
+    dimensions: Option<usize>,
+    input: &[&str],
+) -> anyhow::Result<Vec<Vec<f64>>> {
     let req = EmbedRequest {
-        model: "text-embedding-3-small",
-        input: body,
+        model,
+        input,
+        dimensions,
     };
-    let client = reqwest::blocking::Client::new();
-    let res = client
-        .post("https://api.openai.com/v1/embeddings")
-        .bearer_auth(api_key)
-        .json(&req)
-        .send()?;
-    if res.status() != http::StatusCode::OK {
-        return Err(anyhow::anyhow!("HTTP error {}", res.status()));
+    let mut req_builder = client
+        .post(&format!("{}/embeddings", base_url.trim_end_matches('/')))
+        .json(&req);
+    if let Some(api_key) = api_key {
+        req_builder = req_builder.bearer_auth(api_key);
+    }
+    let mut out: EmbedResponse = client.send_json(req_builder)?;
+    if out.data.len() != input.len() {
+        return Err(anyhow::anyhow!(
+            "Sent {} inputs but got {} embeddings",
+            input.len(),
+            out.data.len()
+        ));
     }
-    let mut out: EmbedResponse = res.json()?;
-    Ok(out.data.remove(0).embedding)
+    out.data.sort_by_key(|e| e.index);
+    Ok(out.data.into_iter().map(|e| e.embedding).collect())
 
     /* Example response
     {
@@ -70,6 +92,8 @@ pub fn embed(body: &str) -> anyhow::Result<Vec<f64>> {
 struct ChatRequest {
     model: &'static str,
     messages: Vec<ChatMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<usize>,
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
@@ -88,15 +112,33 @@ struct ChatResponseChoice {
     message: ChatMessage,
 }
 
-pub fn message(model: &'static str, s: &str, prompts: super::Prompts) -> anyhow::Result<String> {
+pub fn message(
+    client: &api::Client,
+    model: &'static str,
+    s: &str,
+    prompts: super::Prompts,
+    // Longest answer, in tokens. The model's own limit if not set.
+    max_tokens: Option<usize>,
+) -> anyhow::Result<String> {
     let Ok(api_key) = std::env::var("OPENAI_API_KEY") else {
         return Err(anyhow::anyhow!("Set variable OPENAI_API_KEY to your key"));
     };
 
-    let mut messages = vec![ChatMessage {
+    let mut messages = Vec::with_capacity(prompts.examples.len() * 2 + 1);
+    for example in prompts.examples {
+        messages.push(ChatMessage {
+            role: "user".to_string(),
+            content: format!("{}\n\n{}", example.user, example.text),
+        });
+        messages.push(ChatMessage {
+            role: "assistant".to_string(),
+            content: example.answer.clone(),
+        });
+    }
+    messages.push(ChatMessage {
         role: "user".to_string(),
         content: format!("{}\n\n{s}", prompts.user),
-    }];
+    });
     if !prompts.system.is_empty() {
         messages.insert(
             0,
@@ -106,17 +148,16 @@ pub fn message(model: &'static str, s: &str, prompts: super::Prompts) -> anyhow:
             },
         );
     }
-    let req = ChatRequest { model, messages };
-    let client = reqwest::blocking::Client::new();
-    let res = client
-        .post("https://api.openai.com/v1/chat/completions")
+    let req = ChatRequest {
+        model,
+        messages,
+        max_tokens,
+    };
+    let req_builder = client
+        .post(&format!("{API_BASE}/chat/completions"))
         .bearer_auth(api_key)
-        .json(&req)
-        .send()?;
-    if res.status() != http::StatusCode::OK {
-        return Err(anyhow::anyhow!("HTTP error {}", res.status()));
-    }
-    let mut out: ChatResponse = res.json()?;
+        .json(&req);
+    let mut out: ChatResponse = client.send_json(req_builder)?;
     let Some(c0) = out.choices.pop() else {
         return Err(anyhow::anyhow!("No choices in response: {out:?}"));
     };
@@ -0,0 +1,122 @@
+// MIT License
+// Copyright (c) 2024 Graham King
+
+use crate::api;
+use crate::article::Article;
+use crate::db;
+use crate::embedder;
+use crate::index::{Hit, Index};
+
+// Characters of each matching chunk to show
+const SNIPPET_LEN: usize = 200;
+
+pub fn run(
+    db_path: &str,
+    query: &str,
+    limit: usize,
+    is_json: bool,
+    settings: embedder::Settings,
+    http: &api::Settings,
+) -> anyhow::Result<()> {
+    let query = query.trim();
+    if query.is_empty() {
+        anyhow::bail!("Search for what?");
+    }
+    let db_conn = db::open(db_path)?;
+    let index = Index::load(&db_conn)?;
+    let embedder = settings.build(http)?;
+    let query_vec = index.embed_query(embedder.as_ref(), query)?;
+    let hits = index.search(&query_vec, limit);
+
+    if is_json {
+        println!("{}", serde_json::to_string_pretty(&to_json(&hits))?);
+        return Ok(());
+    }
+    if hits.is_empty() {
+        println!("No matches");
+    }
+    for (idx, hit) in hits.iter().enumerate() {
+        let a = hit.article;
+        println!("{}. {} ({:.3})", idx + 1, a.title, hit.score);
+        if a.url.is_empty() {
+            println!("   {}", a.filename.display());
+        } else {
+            println!("   {} ({})", a.url, a.filename.display());
+        }
+        for m in hit.matches.iter() {
+            println!("   - {:.3} {}", m.score, snippet(m.text, a));
+        }
+        println!();
+    }
+    Ok(())
+}
+
+/// The hits as a JSON array, for scripts
+pub fn to_json(hits: &[Hit]) -> serde_json::Value {
+    hits.iter()
+        .map(|hit| {
+            let a = hit.article;
+            serde_json::json!({
+                "title": a.title,
+                "url": a.url,
+                "page_ref": a.page_ref,
+                "filename": a.filename,
+                "score": hit.score,
+                "matches": hit.matches.iter().map(|m| serde_json::json!({
+                    "chunk_id": m.chunk_id,
+                    "score": m.score,
+                    "snippet": snippet(m.text, a),
+                })).collect::<Vec<_>>(),
+            })
+        })
+        .collect()
+}
+
+/// The start of a chunk's text on one line, without the title and date
+/// header every chunk starts with
+pub fn snippet(text: &str, article: &Article) -> String {
+    // Article::parse makes each chunk "{title}\n{date}\n\n{text}", with the date
+    // as the front matter wrote it. A post with no text has only the header.
+    let after_title = text.strip_prefix(article.title.as_str()).unwrap_or(text);
+    let body = after_title.split_once("\n\n").map_or("", |(_, body)| body);
+    let one_line = body.split_whitespace().collect::<Vec<_>>().join(" ");
+    match one_line.char_indices().nth(SNIPPET_LEN) {
+        Some((end, _)) => format!("{}…", one_line[..end].trim_end()),
+        None => one_line,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front_matter::FrontMatter;
+
+    #[test]
+    fn snippet_skips_header() {
+        for date in [
+            "2024-03-01",
+            "2024-03-01T09:30:00.123+01:00",
+            "1 March 2024",
+            "",
+        ] {
+            let fm: FrontMatter =
+                serde_yaml::from_str(&format!("title: Grüße\ndate: '{date}'")).unwrap();
+            let article = Article::from(fm);
+            let chunk = |text: &str| format!("Grüße\n{date}\n\n{text}");
+            assert_eq!(
+                snippet(&chunk("Erster  Absatz.\n\nZweiter\tAbsatz."), &article),
+                "Erster Absatz. Zweiter Absatz.",
+                "{date}"
+            );
+            // A post with no text
+            assert_eq!(snippet(&format!("Grüße\n{date}"), &article), "");
+        }
+
+        let fm: FrontMatter = serde_yaml::from_str("title: T\ndate: 2024-03-01").unwrap();
+        let long = snippet(
+            &format!("T\n2024-03-01\n\n{}", "ä".repeat(300)),
+            &Article::from(fm),
+        );
+        assert_eq!(long, format!("{}…", "ä".repeat(SNIPPET_LEN)));
+    }
+}
@@ -0,0 +1,424 @@
+// MIT License
+// Copyright (c) 2024 Graham King
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::SystemTime;
+
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::api;
+use crate::article::Article;
+use crate::config::Config;
+use crate::db;
+use crate::embedder::{self, Embedder};
+use crate::index::Index;
+use crate::search;
+use crate::similar;
+use crate::similar::strategy::{dot, Scoring};
+
+const DEFAULT_LIMIT: usize = 10;
+const MAX_LIMIT: usize = 100;
+
+// Requests answered at once. Searches spend most of their time waiting for
+// the embedding provider.
+const WORKERS: usize = 4;
+
+// Longest wait for a query's embedding
+const QUERY_TIMEOUT_SECS: u64 = 10;
+
+/// Everything the API answers from, reloaded when the database changes
+struct State {
+    index: Index,
+    // The strategy of the last `similar calc`
+    strategy: String,
+    // Article id to (other article id, similarity), most similar first
+    scores: HashMap<usize, Vec<(usize, f64)>>,
+    // Modified time of the database file when we loaded it
+    db_modified: Option<SystemTime>,
+}
+
+impl State {
+    fn load(db_path: &str) -> anyhow::Result<State> {
+        // Before reading, so a write during the load triggers another one
+        let db_modified = modified(db_path);
+        let db_conn = db::open_read_only(db_path)?;
+        let index = Index::load(&db_conn)?;
+        let strategy = db::get_meta(&db_conn, similar::LAST_STRATEGY)?
+            .unwrap_or_else(|| Scoring::default().key());
+        let mut scores: HashMap<usize, Vec<(usize, f64)>> = HashMap::new();
+        let mut stmt = db_conn.prepare(
+            "SELECT article_a, article_b, similarity FROM article_similarity WHERE strategy = ?1",
+        )?;
+        let mut rows = stmt.query([&strategy])?;
+        while let Some(row) = rows.next()? {
+            let (a, b, similarity): (usize, usize, f64) = (row.get(0)?, row.get(1)?, row.get(2)?);
+            scores.entry(a).or_default().push((b, similarity));
+            scores.entry(b).or_default().push((a, similarity));
+        }
+        for s in scores.values_mut() {
+            s.sort_by(|x, y| y.1.total_cmp(&x.1));
+        }
+        Ok(State {
+            index,
+            strategy,
+            scores,
+            db_modified,
+        })
+    }
+}
+
+pub fn run(
+    db_path: &str,
+    listen: &str,
+    allow_origin: Option<&str>,
+    settings: embedder::Settings,
+    config: &Config,
+) -> anyhow::Result<()> {
+    // Someone is waiting for the answer, so fail fast rather than retry
+    let http = api::Settings {
+        max_retries: 0,
+        timeout_secs: QUERY_TIMEOUT_SECS,
+        connect_timeout_secs: config.http.connect_timeout_secs.min(QUERY_TIMEOUT_SECS),
+        ..config.http.clone()
+    };
+    let embedder = settings.build(&http)?;
+    let state = State::load(db_path)?;
+    // Fail now rather than on the first search
+    if embedder.model() != state.index.model {
+        anyhow::bail!(
+            "The articles were embedded with {}, but queries would be embedded with {}. \
+            Use the same provider and model as `similar embed`.",
+            state.index.model,
+            embedder.model()
+        );
+    }
+    let cors = allow_origin
+        .map(|origin| {
+            Header::from_bytes("Access-Control-Allow-Origin", origin)
+                .map_err(|_| anyhow::anyhow!("Invalid --allow-origin '{origin}'"))
+        })
+        .transpose()?;
+    let server = tiny_http::Server::http(listen)
+        .map_err(|err| anyhow::anyhow!("Listening on {listen}: {err}"))?;
+    let json_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+    println!(
+        "Serving {} articles from {db_path} on http://{listen}",
+        state.index.articles.len()
+    );
+
+    let state = Mutex::new(Arc::new(state));
+    let is_loading = AtomicBool::new(false);
+    thread::scope(|scope| {
+        for _ in 0..WORKERS {
+            scope.spawn(|| {
+                for request in server.incoming_requests() {
+                    let state = current(db_path, &state, &is_loading);
+                    let (status, body) = match handle(&request, &state, embedder.as_ref(), config) {
+                        Ok(body) => (200, body),
+                        Err(Failure(status, msg)) => (status, serde_json::json!({ "error": msg })),
+                    };
+                    let mut response = Response::from_string(body.to_string())
+                        .with_status_code(status)
+                        .with_header(json_type.clone());
+                    if let Some(cors) = cors.as_ref() {
+                        response.add_header(cors.clone());
+                    }
+                    if let Err(err) = request.respond(response) {
+                        eprintln!("Responding: {err}");
+                    }
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+// The state to answer a request from, reloaded first if the database changed.
+// Only one request reloads, without holding the lock, so the others carry on
+// answering from the state they have.
+fn current(db_path: &str, state: &Mutex<Arc<State>>, is_loading: &AtomicBool) -> Arc<State> {
+    let old = Arc::clone(&state.lock().unwrap());
+    if modified(db_path) == old.db_modified || is_loading.swap(true, Ordering::SeqCst) {
+        return old;
+    }
+    let loaded = State::load(db_path);
+    let current = match loaded {
+        Ok(s) => {
+            println!("Reloaded {} articles", s.index.articles.len());
+            let s = Arc::new(s);
+            *state.lock().unwrap() = Arc::clone(&s);
+            s
+        }
+        // Probably mid-write. Keep answering from what we have.
+        Err(err) => {
+            eprintln!("Reloading {db_path}: {err:#}");
+            old
+        }
+    };
+    is_loading.store(false, Ordering::SeqCst);
+    current
+}
+
+/// An HTTP status and an error message for the client
+struct Failure(u16, String);
+
+fn handle(
+    request: &Request,
+    state: &State,
+    embedder: &dyn Embedder,
+    config: &Config,
+) -> Result<serde_json::Value, Failure> {
+    if *request.method() != Method::Get {
+        return Err(Failure(405, "Only GET".to_string()));
+    }
+    // tiny_http gives us the path and query, url needs a base to parse them
+    let url = url::Url::parse("http://localhost")
+        .and_then(|base| base.join(request.url()))
+        .map_err(|err| Failure(400, err.to_string()))?;
+    let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+    let limit = match params.get("limit") {
+        Some(l) => l
+            .parse()
+            .ok()
+            .filter(|l| (1..=MAX_LIMIT).contains(l))
+            .ok_or_else(|| Failure(400, format!("limit must be 1 to {MAX_LIMIT}, not '{l}'")))?,
+        None => DEFAULT_LIMIT,
+    };
+
+    let path = url.path();
+    if path == "/search" {
+        let Some(q) = params.get("q").map(|q| q.trim()).filter(|q| !q.is_empty()) else {
+            return Err(Failure(400, "Missing q".to_string()));
+        };
+        let query = state
+            .index
+            .embed_query(embedder, q)
+            .map_err(|err| Failure(502, format!("{err:#}")))?;
+        Ok(search::to_json(&state.index.search(&query, limit)))
+    } else if path == "/related" {
+        let Some(p) = params.get("path") else {
+            return Err(Failure(400, "Missing path".to_string()));
+        };
+        let Some(article) = state.index.find(p) else {
+            return Err(Failure(404, format!("No article at {p}")));
+        };
+        Ok(related(
+            state,
+            article,
+            &config.related,
+            params.contains_key("limit").then_some(limit),
+        ))
+    } else if let Some(id) = path.strip_prefix("/article/") {
+        let article = id
+            .parse()
+            .ok()
+            .and_then(|id| state.index.article(id))
+            .ok_or_else(|| Failure(404, format!("No article {id}")))?;
+        Ok(article_json(article))
+    } else {
+        Err(Failure(404, format!("Unknown path {path}")))
+    }
+}
+
+/// The posts `similar write` would pick for `article`, with their scores
+fn related(
+    state: &State,
+    article: &Article,
+    config: &similar::related::Settings,
+    limit: Option<usize>,
+) -> serde_json::Value {
+    let cli = similar::related::Settings {
+        count: limit,
+        ..Default::default()
+    };
+    let settings = cli.for_section(config, &article.section);
+    let mut candidates: Vec<(&Article, f64)> = Vec::new();
+    for (id, similarity) in state.scores.get(&article.id).into_iter().flatten() {
+        let Some(candidate) = state.index.article(*id) else {
+            // Draft
+            continue;
+        };
+        if !settings.allows(article, candidate)
+            || candidates
+                .iter()
+                .any(|(c, _)| !c.page_ref.is_empty() && c.page_ref == candidate.page_ref)
+        {
+            continue;
+        }
+        candidates.push((candidate, *similarity));
+    }
+    let picked = settings.pick(candidates, |a, b| {
+        match (state.index.centroid(a.id), state.index.centroid(b.id)) {
+            (Some(a), Some(b)) => dot(a, b),
+            _ => 0.0,
+        }
+    });
+    serde_json::json!({
+        "article": article_json(article),
+        "strategy": state.strategy,
+        "related": picked.iter().map(|(a, score)| {
+            let mut a = article_json(a);
+            a["score"] = serde_json::json!(score);
+            a
+        }).collect::<Vec<_>>(),
+    })
+}
+
+fn article_json(a: &Article) -> serde_json::Value {
+    serde_json::json!({
+        "id": a.id,
+        "title": a.title,
+        "url": a.url,
+        "date": a.date.map(|d| d.to_rfc3339()),
+        "filename": a.filename,
+        "section": a.section,
+        "lang": a.lang,
+        "page_ref": a.page_ref,
+        "tags": a.tags,
+    })
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use tiny_http::TestRequest;
+
+    use super::*;
+
+    struct Fake;
+
+    impl Embedder for Fake {
+        fn model(&self) -> &str {
+            "fake"
+        }
+        fn dimensions(&self) -> Option<usize> {
+            None
+        }
+        fn embed(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f64>>> {
+            Ok(texts
+                .iter()
+                .map(|t| match t.contains("three") {
+                    true => vec![0.0, 1.0],
+                    false => vec![1.0, 0.0],
+                })
+                .collect())
+        }
+    }
+
+    // Three posts, each with one chunk, and their similarity scores
+    fn state(name: &str) -> State {
+        let db_path =
+            std::env::temp_dir().join(format!("hugo-ai-{name}-{}.db", std::process::id()));
+        let db_path = db_path.to_str().unwrap();
+        let _ = fs::remove_file(db_path);
+        let db_conn = db::open(db_path).unwrap();
+        for (id, name, embed) in [
+            (1, "one", [1.0, 0.0]),
+            (2, "two", [0.8, 0.6]),
+            (3, "three", [0.0, 1.0]),
+        ] {
+            db_conn
+                .execute(
+                    r#"INSERT INTO article (id, title, url, filename, is_draft, section, lang, page_ref)
+                    VALUES (?1, ?2, ?3, ?4, 0, 'posts', 'en', ?5)"#,
+                    (
+                        id,
+                        format!("Post {name}"),
+                        format!("https://example.com/posts/{name}/"),
+                        format!("content/posts/{name}.md"),
+                        format!("/posts/{name}"),
+                    ),
+                )
+                .unwrap();
+            db_conn
+                .execute(
+                    r#"INSERT INTO article_chunk (article_id, chunk_id, text, embed, embed_model, embed_dim)
+                    VALUES (?1, 0, ?2, ?3, 'fake', 2)"#,
+                    (
+                        id,
+                        format!("Post {name}\n2024-01-02\n\nAll about {name}"),
+                        similar::f64_vec_to_u8_vec(embed.to_vec()),
+                    ),
+                )
+                .unwrap();
+        }
+        db_conn
+            .execute_batch(
+                r#"INSERT INTO article_similarity (article_a, article_b, strategy, similarity) VALUES
+                    (1, 2, 'mean-pairs', 0.8), (1, 3, 'mean-pairs', 0.0), (2, 3, 'mean-pairs', 0.6)"#,
+            )
+            .unwrap();
+        drop(db_conn);
+        let state = State::load(db_path).unwrap();
+        fs::remove_file(db_path).unwrap();
+        state
+    }
+
+    fn get(state: &State, method: Method, path: &str) -> Result<serde_json::Value, u16> {
+        let request: Request = TestRequest::new()
+            .with_method(method)
+            .with_path(path)
+            .into();
+        handle(&request, state, &Fake, &Config::default()).map_err(|Failure(status, _)| status)
+    }
+
+    #[test]
+    fn endpoints() {
+        let state = state("endpoints");
+        let titles = |v: &serde_json::Value| -> Vec<String> {
+            v.as_array()
+                .unwrap()
+                .iter()
+                .map(|a| a["title"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        let found = get(&state, Method::Get, "/search?q=three%20things").unwrap();
+        assert_eq!(titles(&found), ["Post three", "Post two", "Post one"]);
+        assert_eq!(found[0]["matches"][0]["snippet"], "All about three");
+        let found = get(&state, Method::Get, "/search?q=one&limit=1").unwrap();
+        assert_eq!(titles(&found), ["Post one"]);
+
+        // Post three is below the default min_similarity
+        let related = get(&state, Method::Get, "/related?path=/posts/one/").unwrap();
+        assert_eq!(related["article"]["id"], 1);
+        assert_eq!(related["strategy"], "mean-pairs");
+        assert_eq!(titles(&related["related"]), ["Post two"]);
+        assert_eq!(related["related"][0]["score"], 0.8);
+        let related = get(&state, Method::Get, "/related?path=content/posts/two.md").unwrap();
+        assert_eq!(titles(&related["related"]), ["Post one", "Post three"]);
+
+        let article = get(&state, Method::Get, "/article/3").unwrap();
+        assert_eq!(article["title"], "Post three");
+        assert_eq!(article["url"], "https://example.com/posts/three/");
+    }
+
+    #[test]
+    fn errors() {
+        let state = state("errors");
+        for (method, path, status) in [
+            (Method::Get, "/article/9", 404),
+            (Method::Get, "/article/nine", 404),
+            (Method::Get, "/related?path=/posts/nine/", 404),
+            (Method::Get, "/nowhere", 404),
+            (Method::Post, "/search?q=one", 405),
+            (Method::Get, "/search", 400),
+            (Method::Get, "/search?q=%20", 400),
+            (Method::Get, "/related", 400),
+            (Method::Get, "/search?q=one&limit=ten", 400),
+            (Method::Get, "/search?q=one&limit=0", 400),
+            (Method::Get, "/search?q=one&limit=101", 400),
+            (Method::Get, "/related?path=/posts/one/&limit=1000000", 400),
+        ] {
+            assert_eq!(get(&state, method, path), Err(status), "{path}");
+        }
+        assert!(get(&state, Method::Get, "/search?q=one&limit=100").is_ok());
+    }
+}
@@ -0,0 +1,168 @@
+// MIT License
+// Copyright (c) 2024 Graham King
+
+use std::collections::HashSet;
+use std::fs;
+use std::path;
+
+use anyhow::Context;
+
+#[derive(Clone, Copy)]
+enum Format {
+    Json,
+    Yaml,
+    Toml,
+}
+
+/// A Hugo data file mapping each page to its related pages, for
+/// `similar write --data-file`. Templates read it as `site.Data.<name>`.
+pub struct DataFile {
+    path: path::PathBuf,
+    format: Format,
+    // Language, then the page's logical path, which templates know as .Path,
+    // to its related pages. Translations share a path, so they need the language.
+    pages: serde_json::Map<String, serde_json::Value>,
+    // (language, path) of every page `set` this run
+    written: HashSet<(String, String)>,
+}
+
+impl DataFile {
+    /// Open `path`, keeping what's already in it so a run over some
+    /// of the posts doesn't forget the others
+    pub fn load(path: path::PathBuf) -> anyhow::Result<DataFile> {
+        let format = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Format::Json,
+            Some("yaml" | "yml") => Format::Yaml,
+            Some("toml") => Format::Toml,
+            _ => anyhow::bail!(
+                "{}: data file must end in .json, .yaml, .yml or .toml",
+                path.display()
+            ),
+        };
+        let pages = if path.exists() {
+            let s = fs::read_to_string(&path).with_context(|| format!("{}", path.display()))?;
+            match format {
+                Format::Json => serde_json::from_str(&s)?,
+                Format::Yaml => serde_yaml::from_str(&s)?,
+                Format::Toml => toml::from_str(&s)?,
+            }
+        } else {
+            serde_json::Map::new()
+        };
+        Ok(DataFile {
+            path,
+            format,
+            pages,
+            written: HashSet::new(),
+        })
+    }
+
+    /// Replace the related pages of the page at `page_ref` in language `lang`.
+    /// No related pages removes it.
+    pub fn set(&mut self, lang: &str, page_ref: &str, related: &[String], scores: &[f64]) {
+        // Hugo's .Path is lower case, with dashes for spaces
+        let key = page_ref.to_lowercase().replace(' ', "-");
+        self.written.insert((lang.to_string(), key.clone()));
+        let lang_pages = self
+            .pages
+            .entry(lang)
+            .and_modify(|v| {
+                if !v.is_object() {
+                    // Left by an older version that keyed on the file path
+                    *v = serde_json::Map::new().into();
+                }
+            })
+            .or_insert_with(|| serde_json::Map::new().into())
+            .as_object_mut()
+            .unwrap();
+        if related.is_empty() {
+            lang_pages.remove(&key);
+        } else {
+            let entries: Vec<serde_json::Value> = related
+                .iter()
+                .zip(scores)
+                .map(|(page, score)| serde_json::json!({ "page": page, "score": score }))
+                .collect();
+            lang_pages.insert(key, entries.into());
+        }
+        if lang_pages.is_empty() {
+            self.pages.remove(lang);
+        }
+    }
+
+    /// Forget every page that wasn't `set` this run: deleted, draft, or
+    /// written by an older version. Returns how many went.
+    pub fn remove_unwritten(&mut self) -> usize {
+        let before = self.page_count();
+        let written = &self.written;
+        self.pages.retain(|lang, lang_pages| {
+            let Some(lang_pages) = lang_pages.as_object_mut() else {
+                return false;
+            };
+            lang_pages.retain(|key, _| written.contains(&(lang.clone(), key.clone())));
+            !lang_pages.is_empty()
+        });
+        before - self.page_count()
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.pages
+            .values()
+            .map(|v| v.as_object().map_or(1, |m| m.len()))
+            .sum()
+    }
+
+    /// Write it out, or to stdout if it's a dry run
+    pub fn save(&self, is_dry_run: bool, is_backup: bool) -> anyhow::Result<()> {
+        let s = match self.format {
+            Format::Json => serde_json::to_string_pretty(&self.pages)?,
+            Format::Yaml => serde_yaml::to_string(&self.pages)?,
+            Format::Toml => toml::to_string(&self.pages)?,
+        };
+        if is_dry_run {
+            println!("\n{}:\n{s}", self.path.display());
+            return Ok(());
+        }
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if is_backup && self.path.exists() {
+            let mut bak = self.path.clone();
+            bak.set_extension("BAK");
+            fs::rename(&self.path, bak)?;
+        }
+        fs::write(&self.path, s).with_context(|| format!("{}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyed_by_language_and_path() {
+        let old = serde_json::json!({
+            "en": { "/posts/gone": [], "/posts/kept": [] },
+            "posts/kept.md": [],
+        });
+        let mut data_file = DataFile {
+            path: "data/related.json".into(),
+            format: Format::Json,
+            pages: old.as_object().unwrap().clone(),
+            written: HashSet::new(),
+        };
+        data_file.set("en", "/posts/Kept Post", &["/b".to_string()], &[0.5]);
+        data_file.set("fr", "/posts/Kept Post", &["/c".to_string()], &[0.25]);
+        data_file.set("de", "/posts/nothing-similar", &[], &[]);
+        assert_eq!(data_file.page_count(), 5);
+
+        assert_eq!(data_file.remove_unwritten(), 3);
+        assert_eq!(
+            serde_json::Value::from(data_file.pages),
+            serde_json::json!({
+                "en": { "/posts/kept-post": [{ "page": "/b", "score": 0.5 }] },
+                "fr": { "/posts/kept-post": [{ "page": "/c", "score": 0.25 }] },
+            })
+        );
+    }
+}
@@ -1,239 +1,738 @@
 // MIT License
 // Copyright (c) 2024 Graham King
 
+use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::Write;
 use std::path;
+use std::sync::{mpsc, Mutex};
+use std::thread;
 
 use anyhow::Context;
 use rusqlite::OptionalExtension;
+use sha2::Digest;
 
+use super::api;
 use super::article::Article;
-use super::front_matter::FrontMatter;
+use super::chunk;
+use super::config::Config;
+use super::content;
+use super::db;
+use super::embedder;
+use super::front_matter::Post;
+use super::generated;
+use super::normalize;
+use super::site::Site;
 
-mod db;
+mod data_file;
+pub mod related;
+pub mod strategy;
 
-const MIN_SIMILARITY: f64 = 0.4;
+// Meta key: the strategy of the last calc, which write uses by default
+pub const LAST_STRATEGY: &str = "strategy";
 
 #[derive(clap::Subcommand)]
 pub enum Commands {
     /// 1. Parse markdown articles, chunk them, and store in sqlite db
     Gather {
-        /// The directory to embed
+        /// The Hugo site root, or a directory of Markdown posts
         directory: String,
+        #[clap(flatten)]
+        filter: content::Filter,
+        #[clap(flatten)]
+        chunk: chunk::Settings,
+        #[clap(flatten)]
+        normalize: normalize::Settings,
     },
 
-    /// 2. Call OpenAI's text-embedding-3-small for each chunk, store in db.
-    ///    This part costs money (my whole blog costs less than $0.01) and requires
-    ///    an OpenAI API key in environment variable OPENAI_API_KEY
-    Embed,
+    /// 2. Calculate an embedding for each chunk, store in db.
+    ///    By default this uses OpenAI's text-embedding-3-small, which costs money
+    ///    (my whole blog costs less than $0.01) and requires an OpenAI API key in
+    ///    environment variable OPENAI_API_KEY
+    Embed {
+        #[clap(flatten)]
+        settings: embedder::Settings,
+        /// Replace embeddings made by a different model than the one selected
+        #[clap(long)]
+        reembed: bool,
+    },
 
-    /// 3. Iterate all the articles comparing them pair-wise and store the results in db
-    Calc,
+    /// 3. Compare the articles pair-wise and store the results in db.
+    ///    Only articles whose embeddings changed since the last run are compared again.
+    Calc {
+        /// Compare every pair, not just the ones that changed
+        #[clap(long)]
+        all: bool,
+        #[clap(flatten)]
+        strategy: strategy::Args,
+    },
 
     /// 4. Write a list of related articles to the front-matter of each of your blog posts.
     ///    Backup your files first!
     Write {
-        /// The directory to embed
+        /// The Hugo site root, or a directory of Markdown posts. Same as for gather.
         directory: String,
+        #[clap(flatten)]
+        filter: content::Filter,
         /// Do no backup the file as a .BAK
         #[clap(long)]
         no_backup: bool,
         /// Don't actually change anything, print the changes to stdout
         #[clap(long)]
         dry_run: bool,
+        /// Write the related posts' URLs instead of their paths.
+        /// Needs a Hugo site root so we know the permalinks.
+        #[clap(long)]
+        permalinks: bool,
+        /// Which calc scores to use. Defaults to the strategy of the last calc.
+        #[clap(flatten)]
+        strategy: strategy::Args,
+        #[clap(flatten)]
+        related: related::Settings,
+        #[clap(flatten)]
+        refresh: generated::Refresh,
+        /// Write every post's related posts to this Hugo data file instead of
+        /// editing the posts. Relative to the site root. JSON, YAML or TOML by extension.
+        #[clap(long, value_name = "PATH", num_args = 0..=1, default_missing_value = "data/related.json")]
+        data_file: Option<String>,
     },
 
-    /// Delete before pushing
-    FixUp,
+    /// Remove articles whose file is gone from disk, with their chunks and similarity scores.
+    ///    gather already keeps the embeddings of renamed or moved posts.
+    Prune {
+        /// The Hugo site root, or a directory of Markdown posts. Same as for gather.
+        directory: String,
+        /// Only list the articles that would be removed
+        #[clap(long)]
+        dry_run: bool,
+    },
 }
 
-pub fn run(db_path: &str, cmd: Commands) -> anyhow::Result<()> {
+pub fn run(db_path: &str, config: &Config, cmd: Commands) -> anyhow::Result<()> {
     match cmd {
-        Commands::Gather { directory } => do_gather(db_path, &directory),
-        Commands::Embed => do_embed(db_path),
-        Commands::Calc => do_calc(db_path),
+        Commands::Gather {
+            directory,
+            filter,
+            chunk,
+            normalize,
+        } => do_gather(
+            db_path,
+            &directory,
+            &filter,
+            &chunk.or(&config.chunk),
+            &normalize.or(&config.normalize),
+        ),
+        Commands::Embed { settings, reembed } => {
+            do_embed(db_path, settings.or(&config.embed), &config.http, reembed)
+        }
+        Commands::Calc { all, strategy } => {
+            do_calc(db_path, strategy.scoring().unwrap_or_default(), all)
+        }
         Commands::Write {
             directory,
+            filter,
             no_backup,
             dry_run,
-        } => do_write(db_path, &directory, dry_run, !no_backup),
-        Commands::FixUp => do_fixup(db_path),
+            permalinks,
+            strategy,
+            related,
+            refresh,
+            data_file,
+        } => do_write(
+            db_path,
+            &directory,
+            &filter,
+            dry_run,
+            !no_backup,
+            permalinks,
+            strategy.scoring(),
+            &related,
+            &config.related,
+            &refresh,
+            data_file.as_deref(),
+        ),
+        Commands::Prune { directory, dry_run } => do_prune(db_path, &directory, dry_run),
     }
 }
 
-fn do_gather(db_path: &str, dir: &str) -> anyhow::Result<()> {
-    let db_conn = rusqlite::Connection::open(db_path)?;
-    db_conn.execute(db::CREATE_ARTICLE_TABLE, ())?;
-    db_conn.execute(db::CREATE_CHUNK_TABLE, ())?;
+fn do_gather(
+    db_path: &str,
+    dir: &str,
+    filter: &content::Filter,
+    chunking: &chunk::Settings,
+    normalize: &normalize::Settings,
+) -> anyhow::Result<()> {
+    let mut db_conn = db::open(db_path)?;
+    gather(&mut db_conn, db_path, dir, filter, chunking, normalize)
+}
 
-    let posts: Vec<fs::DirEntry> = fs::read_dir(dir)?.map(|x| x.unwrap()).collect();
+fn gather(
+    db_conn: &mut rusqlite::Connection,
+    db_path: &str,
+    dir: &str,
+    filter: &content::Filter,
+    chunking: &chunk::Settings,
+    normalize: &normalize::Settings,
+) -> anyhow::Result<()> {
+    let site = Site::load(dir)?;
+    let posts = site.pages(filter)?;
     println!("Gathering {} posts from {dir} into {db_path}", posts.len());
 
     // This is so fast we don't need to show progress
-    for entry in posts.into_iter() {
-        let filepath = entry.path();
-        gather_file(&db_conn, &filepath)?;
+    let tx = db_conn.transaction()?;
+
+    // A post that moved looks like a deleted one and a new one with the same text.
+    // Recognise it by the hash of that text, so we keep its embeddings.
+    let missing = missing_articles(&tx, &site.root)?;
+    let mut moved = HashMap::new();
+    for (id, filename) in missing.iter() {
+        let chunks: Vec<String> = load_embed_chunks(&tx, *id)?
+            .into_iter()
+            .map(|(_, text, _)| text)
+            .collect();
+        moved.insert(article_hash(&chunks), (*id, filename.clone()));
+    }
+
+    let embedded_before = count_embedded_chunks(&tx)?;
+    let mut new_count = 0;
+    let mut changed = Vec::new();
+    let mut renamed = Vec::new();
+    for page in posts.into_iter() {
+        let g = gather_file(&tx, &site, &page, chunking, normalize, &mut moved)?;
+        if let Some((id, old_filename)) = g.renamed_from.as_ref() {
+            renamed.push((*id, old_filename.clone(), page.rel_path.clone()));
+        }
+        if g.is_new {
+            new_count += 1;
+        } else if g.changed_chunks + g.added_chunks + g.removed_chunks > 0 {
+            changed.push((page.rel_path, g));
+        }
+    }
+    let cleared = embedded_before.saturating_sub(count_embedded_chunks(&tx)?);
+    tx.commit()?;
+
+    println!("{new_count} new articles, {} changed", changed.len());
+    for (rel_path, g) in changed.iter() {
+        println!(
+            "Changed: {}: {} chunks edited, {} added, {} removed",
+            rel_path.display(),
+            g.changed_chunks,
+            g.added_chunks,
+            g.removed_chunks
+        );
+    }
+    if !changed.is_empty() {
+        println!("Their embeddings and similarity scores were cleared. Run embed and calc again.");
+    }
+    for (_, old_filename, rel_path) in renamed.iter() {
+        println!("Renamed: {old_filename} -> {}", rel_path.display());
+    }
+    if cleared * 2 > embedded_before {
+        // Not edits. The chunks themselves are different.
+        println!(
+            "Warning: {cleared} of {embedded_before} embedded chunks changed and need embedding again. \
+            Expected after upgrading from a version that chunked by word count, or after changing \
+            the chunk or normalize settings. Otherwise check --chunk-tokens, --chunk-overlap, \
+            --drop-code, and [chunk] and [normalize] in the config file before running embed."
+        );
+    }
+
+    let gone: Vec<&String> = missing
+        .iter()
+        .filter(|(id, _)| !renamed.iter().any(|(r, _, _)| r == id))
+        .map(|(_, filename)| filename)
+        .collect();
+    if !gone.is_empty() {
+        println!(
+            "{} articles in the database are no longer in {dir}:",
+            gone.len()
+        );
+        for filename in gone {
+            println!("Missing: {filename}");
+        }
+        println!("Run `hugo-ai similar prune {dir}` to remove them.");
     }
 
     Ok(())
 }
 
-fn do_embed(db_path: &str) -> anyhow::Result<()> {
-    let mut db_conn = rusqlite::Connection::open(db_path)?;
+fn do_prune(db_path: &str, dir: &str, is_dry_run: bool) -> anyhow::Result<()> {
+    let mut db_conn = db::open(db_path)?;
+    prune(&mut db_conn, db_path, dir, is_dry_run)
+}
 
-    let articles = load_all_active_articles(&db_conn)?;
-    let total = articles.len();
-    println!("Embedding {total} non-draft articles");
+fn prune(
+    db_conn: &mut rusqlite::Connection,
+    db_path: &str,
+    dir: &str,
+    is_dry_run: bool,
+) -> anyhow::Result<()> {
+    let site = Site::load(dir)?;
 
-    let width = get_terminal_width();
-    let mut stdout = io::stdout();
-    for (idx, article) in articles.into_iter().enumerate() {
-        let progress = format!("{} / {total}", idx + 1);
-        let spaces = " ".repeat(width - (article.title.len() + progress.len() + 2));
-        write!(stdout, "\r[{}{spaces}{progress}]", article.title)?;
-        stdout.flush()?;
+    let total: usize = db_conn.query_row("SELECT COUNT(*) FROM article", (), |row| row.get(0))?;
+    let missing = missing_articles(db_conn, &site.root)?;
+    if missing.is_empty() {
+        println!("Nothing to prune, all {total} articles are in {dir}");
+        return Ok(());
+    }
+    if missing.len() == total {
+        // Almost certainly the wrong directory, not a deleted blog
+        anyhow::bail!(
+            "None of the {total} articles in {db_path} are in {dir}. \
+            Is it the same directory you gave to gather?"
+        );
+    }
 
-        let tx = db_conn.transaction()?;
-        let mut stmt = tx.prepare(
-            "UPDATE article_chunk SET embed = ?1 WHERE chunk_id = ?2 AND article_id = ?3",
-        )?;
-        let chunks = load_embed_chunks(&tx, article.id)?;
-        for (chunk_id, text, current_embed) in chunks {
-            if !current_embed.is_empty() {
-                // embeds cost money, don't recalculate existing ones
-                // this means if the text changes need to edit db to force this
-                continue;
-            }
-            let embed = super::openai::embed(&text)?;
-            stmt.execute((f64_vec_to_u8_vec(embed), chunk_id, article.id))?;
+    let tx = db_conn.transaction()?;
+    for (id, filename) in missing.iter() {
+        if is_dry_run {
+            println!("Would remove: {filename}");
+        } else {
+            delete_article(&tx, *id)?;
+            println!("Removed: {filename}");
         }
-        stmt.finalize()?;
-        tx.commit()?;
     }
-    println!();
+    tx.commit()?;
+    if !is_dry_run {
+        println!("Removed {} of {total} articles", missing.len());
+    }
+
     Ok(())
 }
 
-fn cosine_similarity(a: &[f64], b: &[f64]) -> f64 {
-    if a.len() != b.len() {
-        panic!("Vectors a and b must be of the same length");
+// Articles whose file isn't in the directory any more, as (id, filename)
+fn missing_articles(
+    db_conn: &rusqlite::Connection,
+    root: &path::Path,
+) -> anyhow::Result<Vec<(usize, String)>> {
+    let mut stmt = db_conn.prepare("SELECT id, filename FROM article ORDER BY filename")?;
+    let rows = stmt.query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let mut missing = Vec::new();
+    for row in rows {
+        let (id, filename): (usize, String) = row?;
+        if !root.join(&filename).is_file() {
+            missing.push((id, filename));
+        }
     }
+    Ok(missing)
+}
 
-    let dot_product: f64 = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum();
+fn delete_article(db_conn: &rusqlite::Connection, article_id: usize) -> anyhow::Result<()> {
+    db_conn.execute(
+        "DELETE FROM article_similarity WHERE article_a = ?1 OR article_b = ?1",
+        [article_id],
+    )?;
+    db_conn.execute(
+        "DELETE FROM article_chunk WHERE article_id = ?1",
+        [article_id],
+    )?;
+    db_conn.execute(
+        "DELETE FROM generated WHERE filename = (SELECT filename FROM article WHERE id = ?1)",
+        [article_id],
+    )?;
+    db_conn.execute("DELETE FROM article WHERE id = ?1", [article_id])?;
+    Ok(())
+}
 
-    let magnitude_a: f64 = a.iter().map(|x| x * x).sum::<f64>().sqrt();
-    let magnitude_b: f64 = b.iter().map(|x| x * x).sum::<f64>().sqrt();
+// A chunk of an article waiting for its embedding
+struct ChunkToEmbed {
+    article_id: usize,
+    chunk_id: usize,
+    text: String,
+}
 
-    dot_product / (magnitude_a * magnitude_b)
+fn do_embed(
+    db_path: &str,
+    settings: embedder::Settings,
+    http: &api::Settings,
+    is_reembed: bool,
+) -> anyhow::Result<()> {
+    let embedder = settings.build(http)?;
+    let mut db_conn = db::open(db_path)?;
+    embed_all(&mut db_conn, embedder.as_ref(), &settings, is_reembed)
 }
 
-fn do_calc(db_path: &str) -> anyhow::Result<()> {
-    let mut db_conn = rusqlite::Connection::open(db_path)?;
-    db_conn.execute(db::CREATE_SIMILARITY_TABLE, ())?;
+fn embed_all(
+    db_conn: &mut rusqlite::Connection,
+    embedder: &dyn embedder::Embedder,
+    settings: &embedder::Settings,
+    is_reembed: bool,
+) -> anyhow::Result<()> {
+    // Vectors from different models can't be compared, so don't mix them
+    let mismatched = count_mismatched_chunks(db_conn, embedder)?;
+    if mismatched > 0 && !is_reembed {
+        anyhow::bail!(
+            "{mismatched} chunks were embedded with a different model or dimensions than {}. \
+            Add --reembed to replace them.",
+            embedder.model()
+        );
+    }
 
-    let articles = load_all_active_articles(&db_conn)?;
+    let articles = load_all_active_articles(db_conn)?;
+    let mut titles = HashMap::new();
+    let mut todo = Vec::new();
+    for article in articles.into_iter() {
+        // embeds cost money, don't recalculate existing ones.
+        // gather clears the embedding when the text changes.
+        for (chunk_id, text) in load_unembedded_chunks(db_conn, article.id, embedder)? {
+            todo.push(ChunkToEmbed {
+                article_id: article.id,
+                chunk_id,
+                text,
+            });
+        }
+        titles.insert(article.id, article.title);
+    }
+    let total = todo.len();
+    println!(
+        "Embedding {total} chunks of {} non-draft articles with {}",
+        titles.len(),
+        embedder.model()
+    );
+
+    // Workers take batches off the queue and send back the embeddings.
+    // Only this thread touches the database, committing each batch as it arrives,
+    // so an interrupted run keeps everything done so far.
+    let mut batches = Vec::new();
+    let mut it = todo.into_iter().peekable();
+    while it.peek().is_some() {
+        batches.push(it.by_ref().take(settings.batch_size()).collect::<Vec<_>>());
+    }
+    let queue = Mutex::new(batches.into_iter());
+    let (tx, rx) = mpsc::channel::<anyhow::Result<(Vec<ChunkToEmbed>, Vec<Vec<f64>>)>>();
+
+    let width = get_terminal_width();
+    let mut stdout = io::stdout();
+    thread::scope(|scope| {
+        for _ in 0..settings.workers() {
+            let tx = tx.clone();
+            let queue = &queue;
+            scope.spawn(move || loop {
+                let Some(batch) = queue.lock().unwrap().next() else {
+                    break;
+                };
+                let texts: Vec<&str> = batch.iter().map(|c| c.text.as_str()).collect();
+                let res = embedder.embed(&texts).map(|embeds| (batch, embeds));
+                let is_err = res.is_err();
+                if tx.send(res).is_err() || is_err {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+
+        let mut done = 0;
+        let mut first_err = None;
+        for res in rx {
+            let (batch, embeds) = match res {
+                Ok(r) => r,
+                Err(err) => {
+                    // Stop handing out work, but save what is already in flight
+                    queue.lock().unwrap().by_ref().for_each(drop);
+                    first_err.get_or_insert(err);
+                    continue;
+                }
+            };
+            save_embeddings(db_conn, embedder, &batch, embeds)?;
+            done += batch.len();
+
+            let title = &titles[&batch[batch.len() - 1].article_id];
+            show_progress(&mut stdout, width, title, done, total)?;
+        }
+        println!();
+        match first_err {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    })
+}
+
+fn save_embeddings(
+    db_conn: &mut rusqlite::Connection,
+    embedder: &dyn embedder::Embedder,
+    batch: &[ChunkToEmbed],
+    embeds: Vec<Vec<f64>>,
+) -> anyhow::Result<()> {
+    let tx = db_conn.transaction()?;
+    let mut stmt = tx.prepare(
+        r#"UPDATE article_chunk
+        SET embed = ?1, embed_model = ?2, embed_dim = ?3, embed_dim_requested = ?4
+        WHERE chunk_id = ?5 AND article_id = ?6"#,
+    )?;
+    for (chunk, embed) in batch.iter().zip(embeds) {
+        let dim = embed.len();
+        stmt.execute((
+            f64_vec_to_u8_vec(embed),
+            embedder.model(),
+            dim,
+            embedder.dimensions(),
+            chunk.chunk_id,
+            chunk.article_id,
+        ))?;
+    }
+    stmt.finalize()?;
+    let mut article_ids: Vec<usize> = batch.iter().map(|c| c.article_id).collect();
+    article_ids.dedup();
+    for article_id in article_ids {
+        db::mark_changed(&tx, article_id)?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+fn do_calc(db_path: &str, scoring: strategy::Scoring, is_all: bool) -> anyhow::Result<()> {
+    let mut db_conn = db::open(db_path)?;
+    calc(&mut db_conn, scoring, is_all)?;
+    Ok(())
+}
+
+// Score the pairs that involve a changed article. Returns how many it scored.
+fn calc(
+    db_conn: &mut rusqlite::Connection,
+    scoring: strategy::Scoring,
+    is_all: bool,
+) -> anyhow::Result<usize> {
+    let models = embed_models(db_conn)?;
+    if models.len() > 1 {
+        anyhow::bail!(
+            "Embeddings from different models can't be compared. Found: {}. \
+            Run embed again with --reembed and the model you want.",
+            models.join(", ")
+        );
+    }
+
+    // Articles with an embed_version newer than this changed since the last
+    // calc with this strategy
+    let strategy = scoring.key();
+    let calc_version_key = format!("calc_version:{strategy}");
+    let last_calc: u64 = match db::get_meta(db_conn, &calc_version_key)? {
+        Some(v) if !is_all => v.parse()?,
+        _ => 0,
+    };
+    let embed_version = db::embed_version(db_conn)?;
+
+    // Everything in memory once. For 2,000 posts that's a few hundred MB at most.
+    let embeds = load_all_embeddings(db_conn)?;
+    let mut articles = Vec::new();
+    for (article, version) in load_embed_versions(db_conn)? {
+        if let Some(chunks) = embeds.get(&article.id) {
+            articles.push((article, version, chunks));
+        }
+    }
+    let is_changed = |version: u64| version > last_calc;
+    let changed_count = articles.iter().filter(|(_, v, _)| is_changed(*v)).count();
+    // Each changed article against every other, counting pairs of changed articles once
+    let total = changed_count * articles.len().saturating_sub(1)
+        - changed_count * changed_count.saturating_sub(1) / 2;
     println!(
-        "Calculating similarity for {} non-draft articles",
+        "Calculating {strategy} similarity for {changed_count} of {} embedded non-draft articles, {total} pairs",
         articles.len()
     );
 
-    let mut count = 0;
-    for (idx, a) in articles.iter().enumerate() {
+    let width = get_terminal_width();
+    let mut stdout = io::stdout();
+    let mut done = 0;
+    for (a, a_version, a_chunks) in articles.iter() {
+        if !is_changed(*a_version) {
+            continue;
+        }
         // Do one article at a time
         let tx = db_conn.transaction()?;
         let mut stmt = tx.prepare(
-            r#"INSERT INTO article_similiarity (article_a, article_b, similarity)
-               VALUES (?1, ?2, ?3)
+            r#"INSERT INTO article_similarity (article_a, article_b, strategy, similarity)
+               VALUES (?1, ?2, ?3, ?4)
                ON CONFLICT DO UPDATE SET similarity = excluded.similarity
             "#,
         )?;
-        for b in articles.iter().skip(idx + 1) {
-            let similarity = compare_articles(&tx, a, b)?;
-            stmt.execute((a.id, b.id, similarity))?;
-            println!(
-                "{count}: {} {} -> {similarity}",
-                a.filename.file_name().unwrap().to_string_lossy(),
-                b.filename.file_name().unwrap().to_string_lossy()
-            );
-            count += 1;
+        for (b, b_version, b_chunks) in articles.iter() {
+            if a.id == b.id || (is_changed(*b_version) && b.id < a.id) {
+                // Same article, or a pair we already did
+                continue;
+            }
+            let similarity = scoring.score(a_chunks, b_chunks)?;
+            stmt.execute((a.id.min(b.id), a.id.max(b.id), &strategy, similarity))?;
+            done += 1;
         }
         stmt.finalize()?;
         tx.commit()?;
+        show_progress(&mut stdout, width, &a.title, done, total)?;
+    }
+    if changed_count > 0 {
+        println!();
     }
+    db::set_meta(db_conn, &calc_version_key, &embed_version.to_string())?;
+    db::set_meta(db_conn, LAST_STRATEGY, &strategy)?;
 
-    Ok(())
+    Ok(done)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn do_write(
     db_path: &str,
     directory: &str,
+    filter: &content::Filter,
     is_dry_run: bool,
     is_backup: bool,
+    use_permalinks: bool,
+    scoring: Option<strategy::Scoring>,
+    related_cli: &related::Settings,
+    related_config: &related::Settings,
+    refresh: &generated::Refresh,
+    data_file: Option<&str>,
 ) -> anyhow::Result<()> {
-    let db_conn = rusqlite::Connection::open(db_path)?;
-    let articles = load_all_active_articles(&db_conn)?;
-    let dir = path::PathBuf::from(directory);
+    let db_conn = db::open(db_path)?;
+    let site = Site::load(directory)?;
+    let mut data_file = data_file
+        .map(|f| data_file::DataFile::load(site.root.join(f)))
+        .transpose()?;
+    if use_permalinks && !site.is_hugo_site() {
+        anyhow::bail!("--permalinks needs a Hugo site root, {directory} has no config file");
+    }
+    let strategy = match scoring {
+        Some(s) => s.key(),
+        None => db::get_meta(&db_conn, LAST_STRATEGY)?
+            .unwrap_or_else(|| strategy::Scoring::default().key()),
+    };
+    let score_count: usize = db_conn.query_row(
+        "SELECT COUNT(*) FROM article_similarity WHERE strategy = ?1",
+        [&strategy],
+        |row| row.get(0),
+    )?;
+    if score_count == 0 {
+        anyhow::bail!("No {strategy} similarity scores. Run calc with that strategy first.");
+    }
+    let articles: HashMap<usize, Article> = load_all_active_articles(&db_conn)?
+        .into_iter()
+        .map(|a| (a.id, a))
+        .collect();
+    let by_filename: HashMap<&path::Path, &Article> = articles
+        .values()
+        .map(|a| (a.filename.as_path(), a))
+        .collect();
+    let pages = site.pages(filter)?;
     println!(
-        "Calculating similar articles for {} non-draft posts in {directory}",
+        "Calculating similar articles for {} non-draft posts in {directory}, by {strategy} similarity",
         articles.len()
     );
     let width = if is_dry_run { get_terminal_width() } else { 0 };
 
     let mut stmt_first = db_conn.prepare(
         r#"
-        SELECT a.filename, s.similarity
-        FROM article_similiarity s, article a
-        WHERE NOT a.is_draft
+        SELECT a.id, a.filename, a.page_ref, a.url, s.similarity
+        FROM article_similarity s, article a
+        WHERE NOT a.is_draft AND s.strategy = ?2
          AND ((s.article_a = ?1 AND s.article_b = a.id) OR (s.article_a = a.id AND s.article_b = ?1))
-        ORDER BY s.similarity DESC
-        LIMIT 3"#,
+        ORDER BY s.similarity DESC"#,
     )?;
 
+    // Only loaded if MMR needs them
+    let mut embeds = HashMap::new();
     let mut written_count = 0;
-    for article in articles {
-        let mut related = Vec::new();
-        let results = stmt_first.query_map([article.id], |row| {
-            let filename: String = row.get(0)?;
-            let similarity: f64 = row.get(1)?;
-            Ok((filename, similarity))
+    for page in pages {
+        let Some(&article) = by_filename.get(page.rel_path.as_path()) else {
+            // Draft, or not gathered yet
+            continue;
+        };
+        let settings = related_cli.for_section(related_config, &article.section);
+        if settings.mmr_lambda.is_some() && embeds.is_empty() {
+            // MMR compares the candidates with each other
+            embeds = load_all_embeddings(&db_conn)?;
+        }
+        let mut candidates = Vec::new();
+        let results = stmt_first.query_map((article.id, &strategy), |row| {
+            let id: usize = row.get(0)?;
+            let filename: String = row.get(1)?;
+            let page_ref: String = row.get(2)?;
+            let url: String = row.get(3)?;
+            let similarity: f64 = row.get(4)?;
+            Ok((id, filename, page_ref, url, similarity))
         })?;
-        for (filename, similarity) in results.map(|x| x.unwrap()) {
-            if similarity < MIN_SIMILARITY {
+        for (id, filename, page_ref, url, similarity) in results.map(|x| x.unwrap()) {
+            if !site.root.join(&filename).is_file() {
+                // Deleted since gather, and not pruned yet
+                continue;
+            }
+            if !articles
+                .get(&id)
+                .is_some_and(|c| settings.allows(article, c))
+            {
                 continue;
             }
-            let p = path::PathBuf::from(filename);
-            let os_name = p.file_name().unwrap();
-            related.push(os_name.to_string_lossy().to_string());
+            let entry = if use_permalinks && !url.is_empty() {
+                url
+            } else if !page_ref.is_empty() {
+                page_ref
+            } else {
+                // Gathered by an older version
+                content::page_ref(path::Path::new(&filename))
+            };
+            if candidates.iter().any(|((_, e), _)| *e == entry) {
+                // Translations share a page_ref
+                continue;
+            }
+            candidates.push(((id, entry), similarity));
         }
+        let picked = settings.pick(candidates, |(a, _), (b, _)| {
+            match (embeds.get(a), embeds.get(b)) {
+                (Some(a), Some(b)) => a.centroid_similarity(b),
+                _ => 0.0,
+            }
+        });
+        let (related, scores): (Vec<String>, Vec<f64>) = picked
+            .into_iter()
+            .map(|((_, entry), score)| (entry, (score * 1000.0).round() / 1000.0))
+            .unzip();
 
+        if let Some(data_file) = data_file.as_mut() {
+            let lang = match page.lang.as_str() {
+                "" => site.default_language(),
+                lang => lang,
+            };
+            data_file.set(lang, &site.page_ref(&page), &related, &scores);
+            continue;
+        }
         if related.is_empty() {
             // No other articles are similar enough
             continue;
         }
 
-        let full_path = dir.join(&article.filename);
+        let full_path = page.path;
         let contents =
             fs::read_to_string(&full_path).with_context(|| format!("{}", full_path.display()))?;
-        let (mut fm, fm_size) = FrontMatter::extract(&contents)?;
-        if !fm.related.is_empty() {
+        let post = Post::split(&contents).with_context(|| format!("{}", full_path.display()))?;
+        let fields: serde_json::Map<String, serde_json::Value> = post
+            .parse()
+            .with_context(|| format!("{}", full_path.display()))?;
+        let filename = article.filename.to_string_lossy();
+        if fields.contains_key("related")
+            && !refresh.should_replace(&db_conn, &filename, "related")?
+        {
             // Don't overwrite existing related articles
             continue;
         }
-        let body: String = contents
-            .lines()
-            .skip(fm_size + 2) // Add the two dashes lines we must also skip
-            .collect::<Vec<&str>>()
-            .join("\n");
-
-        fm.related = related;
-        let y = serde_yaml::to_string(&fm)?;
+        let related = serde_json::json!(related);
+        let scores = serde_json::json!(scores);
+        // Keep the scores in step with the list if they were written before
+        let with_scores = settings.scores() || fields.contains_key("related_scores");
+        if fields.get("related") == Some(&related)
+            && (!with_scores || fields.get("related_scores") == Some(&scores))
+        {
+            // Still the same, no need to touch the file
+            if !is_dry_run {
+                generated::record(&db_conn, &filename, "related")?;
+            }
+            continue;
+        }
+        let mut updated = post.with_field("related", &related)?;
+        if with_scores {
+            updated = Post::split(&updated)?.with_field("related_scores", &scores)?;
+        }
 
         let mut writer: Box<dyn io::Write> = if is_dry_run {
-            let article_changed = article.filename.file_name().unwrap().to_string_lossy();
-            let spaces = "+".repeat((width - (article_changed.len() + 2)) / 2);
+            let article_changed = article.filename.to_string_lossy();
+            let spaces = "+".repeat(width.saturating_sub(article_changed.len() + 2) / 2);
             println!("\n\n{spaces} {article_changed} {spaces}");
             Box::new(io::stdout())
         } else if is_backup {
@@ -244,22 +743,41 @@ fn do_write(
         } else {
             Box::new(File::create(&full_path)?)
         };
-        writeln!(writer, "---")?;
-        write!(writer, "{y}")?;
-        writeln!(writer, "---")?;
-        write!(writer, "{body}")?;
+        writer.write_all(updated.as_bytes())?;
+        if !is_dry_run {
+            generated::record(&db_conn, &filename, "related")?;
+        }
 
         written_count += 1;
     }
+    if let Some(mut data_file) = data_file {
+        // Only a run over every post knows which ones are gone
+        if filter.include.is_empty() && filter.exclude.is_empty() {
+            let removed = data_file.remove_unwritten();
+            if removed > 0 {
+                println!(
+                    "\nRemoved {removed} posts that are gone, drafts or from an older version from the data file"
+                );
+            }
+        }
+        data_file.save(is_dry_run, is_backup)?;
+        println!(
+            "\n{} posts have related posts in the data file",
+            data_file.page_count()
+        );
+        return Ok(());
+    }
     println!("\nUpdated {written_count} posts");
 
     Ok(())
 }
 
 // Does not include draft articles
-fn load_all_active_articles(db_conn: &rusqlite::Connection) -> anyhow::Result<Vec<Article>> {
-    let mut stmt = db_conn
-        .prepare("select id, title, url, date, filename, is_draft from article order by id")?;
+pub fn load_all_active_articles(db_conn: &rusqlite::Connection) -> anyhow::Result<Vec<Article>> {
+    let mut stmt = db_conn.prepare(
+        r#"select id, title, url, date, filename, section, lang, page_ref, is_draft, tags, params
+        from article order by id"#,
+    )?;
     let article_iter = stmt.query_map((), |row| {
         // Convert each row into an Article instance
         let id: usize = row.get(0)?;
@@ -267,7 +785,12 @@ fn load_all_active_articles(db_conn: &rusqlite::Connection) -> anyhow::Result<Ve
         let url: String = row.get(2)?;
         let date: Option<String> = row.get(3)?; // Dates are stored as strings in SQLite
         let filename: String = row.get(4)?;
-        let is_draft: bool = row.get(5)?;
+        let section: String = row.get(5)?;
+        let lang: String = row.get(6)?;
+        let page_ref: String = row.get(7)?;
+        let is_draft: bool = row.get(8)?;
+        let tags: String = row.get(9)?;
+        let params: String = row.get(10)?;
 
         // Attempt to parse the date if it exists
         let date = date
@@ -280,7 +803,12 @@ fn load_all_active_articles(db_conn: &rusqlite::Connection) -> anyhow::Result<Ve
             url,
             date,
             filename: path::PathBuf::from(filename),
+            section,
+            lang,
+            page_ref,
             is_draft,
+            tags: serde_json::from_str(&tags).unwrap_or_default(),
+            params: serde_json::from_str(&params).unwrap_or_default(),
             chunks: vec![],
         })
     })?;
@@ -294,21 +822,56 @@ fn load_all_active_articles(db_conn: &rusqlite::Connection) -> anyhow::Result<Ve
     Ok(articles)
 }
 
-fn compare_articles(
+// Article id -> its chunks' embeddings.
+// Articles with a chunk still waiting for its embedding are left out.
+fn load_all_embeddings(
     db_conn: &rusqlite::Connection,
-    a: &Article,
-    b: &Article,
-) -> anyhow::Result<f64> {
-    let a_chunks = load_embed_chunks(db_conn, a.id)?;
-    let b_chunks = load_embed_chunks(db_conn, b.id)?;
-    let mut simis = Vec::new();
-    for (_, _, a_embedding) in a_chunks.into_iter() {
-        for (_, _, b_embedding) in b_chunks.iter() {
-            let v = cosine_similarity(&a_embedding, b_embedding);
-            simis.push(v);
+) -> anyhow::Result<HashMap<usize, strategy::Embedded>> {
+    let mut chunks: HashMap<usize, Vec<(Vec<f64>, usize)>> = HashMap::new();
+    let mut incomplete = Vec::new();
+    let mut stmt = db_conn.prepare(
+        "SELECT article_id, embed, length(text) FROM article_chunk ORDER BY article_id, chunk_id",
+    )?;
+    let mut rows = stmt.query(())?;
+    while let Some(row) = rows.next()? {
+        let article_id: usize = row.get(0)?;
+        match row.get::<_, Option<Vec<u8>>>(1)? {
+            Some(blob) => chunks
+                .entry(article_id)
+                .or_default()
+                .push((u8_vec_to_f64_vec(blob), row.get(2)?)),
+            None => incomplete.push(article_id),
         }
     }
-    Ok(simis.iter().sum::<f64>() / simis.len() as f64)
+    for article_id in incomplete {
+        chunks.remove(&article_id);
+    }
+    chunks
+        .into_iter()
+        .map(|(article_id, c)| {
+            let embedded =
+                strategy::Embedded::new(c).with_context(|| format!("article_id={article_id}"))?;
+            Ok((article_id, embedded))
+        })
+        .collect()
+}
+
+// Non-draft articles and when their embeddings last changed
+fn load_embed_versions(db_conn: &rusqlite::Connection) -> anyhow::Result<Vec<(Article, u64)>> {
+    let mut versions: HashMap<usize, u64> = HashMap::new();
+    let mut stmt = db_conn.prepare("SELECT id, embed_version FROM article")?;
+    let rows = stmt.query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?;
+    for row in rows {
+        let (id, version) = row?;
+        versions.insert(id, version);
+    }
+    Ok(load_all_active_articles(db_conn)?
+        .into_iter()
+        .map(|a| {
+            let version = versions.get(&a.id).copied().unwrap_or_default();
+            (a, version)
+        })
+        .collect())
 }
 
 fn load_embed_chunks(
@@ -332,28 +895,139 @@ fn load_embed_chunks(
     Ok(out)
 }
 
-// - Parse the post metadata as yaml
+// How many chunks have an embedding from a different model, or of a different
+// length than `embedder` asks for. No --dimensions is different from any.
+fn count_mismatched_chunks(
+    db_conn: &rusqlite::Connection,
+    embedder: &dyn embedder::Embedder,
+) -> anyhow::Result<usize> {
+    Ok(db_conn.query_row(
+        r#"SELECT COUNT(*) FROM article_chunk
+        WHERE embed IS NOT NULL
+         AND (embed_model IS NOT ?1 OR embed_dim_requested IS NOT ?2)"#,
+        (embedder.model(), embedder.dimensions()),
+        |row| row.get(0),
+    )?)
+}
+
+// Chunks with no embedding, or one made by a different model
+fn load_unembedded_chunks(
+    db_conn: &rusqlite::Connection,
+    article_id: usize,
+    embedder: &dyn embedder::Embedder,
+) -> anyhow::Result<Vec<(usize, String)>> {
+    let mut stmt = db_conn.prepare(
+        r#"SELECT chunk_id, text FROM article_chunk
+        WHERE article_id = ?1
+         AND (embed IS NULL OR embed_model IS NOT ?2 OR embed_dim_requested IS NOT ?3)"#,
+    )?;
+    let rows = stmt.query_map(
+        (article_id, embedder.model(), embedder.dimensions()),
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+// Every distinct model and vector length in the database, e.g. "text-embedding-3-small/1536"
+fn embed_models(db_conn: &rusqlite::Connection) -> anyhow::Result<Vec<String>> {
+    let mut stmt = db_conn.prepare(
+        r#"SELECT DISTINCT embed_model, embed_dim FROM article_chunk
+        WHERE embed IS NOT NULL ORDER BY 1, 2"#,
+    )?;
+    let rows = stmt.query_map((), |row| {
+        let model: String = row.get(0)?;
+        let dim: usize = row.get(1)?;
+        Ok(format!("{model}/{dim}"))
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+fn count_embedded_chunks(db_conn: &rusqlite::Connection) -> anyhow::Result<usize> {
+    Ok(db_conn.query_row(
+        "SELECT COUNT(*) FROM article_chunk WHERE embed IS NOT NULL",
+        (),
+        |row| row.get(0),
+    )?)
+}
+
+// What gather_file did to an article's chunks
+struct Gathered {
+    is_new: bool,
+    // The article was in the database under this (id, filename), and that file is gone
+    renamed_from: Option<(usize, String)>,
+    // Text differs, so the embedding was cleared
+    changed_chunks: usize,
+    added_chunks: usize,
+    // The article got shorter
+    removed_chunks: usize,
+}
+
+// - Parse the post metadata
 // - Insert it into article table
 // - Calculate the chunks
 // - Insert them into article_chunk
-fn gather_file(db_conn: &rusqlite::Connection, filepath: &path::Path) -> anyhow::Result<Article> {
+fn gather_file(
+    db_conn: &rusqlite::Connection,
+    site: &Site,
+    page: &content::Page,
+    chunking: &chunk::Settings,
+    normalize: &normalize::Settings,
+    // Article hash -> (id, filename) of articles whose file is missing
+    moved: &mut HashMap<String, (usize, String)>,
+) -> anyhow::Result<Gathered> {
+    let filepath = &page.path;
     let contents = fs::read_to_string(filepath)?;
-    let article = Article::parse(filepath, &contents)?;
+    let article = Article::parse(site, page, &contents, chunking, normalize)
+        .with_context(|| format!("{}", filepath.display()))?;
+
+    let filename = page.rel_path.to_string_lossy();
+    let was_draft: Option<bool> = db_conn
+        .query_row(
+            "SELECT is_draft FROM article WHERE filename = ?1",
+            [&filename],
+            |row| row.get(0),
+        )
+        .optional()?;
+    let mut renamed_from = None;
+    if was_draft.is_none() {
+        if let Some((id, old_filename)) = moved.remove(&article_hash(&article.chunks)) {
+            // Same text under a new name. Keep the id, and with it the
+            // chunks, embeddings and similarity scores.
+            db_conn.execute(
+                "UPDATE article SET filename = ?1 WHERE id = ?2",
+                (&filename, id),
+            )?;
+            db_conn.execute(
+                "UPDATE generated SET filename = ?1 WHERE filename = ?2",
+                (&filename, &old_filename),
+            )?;
+            renamed_from = Some((id, old_filename));
+        }
+    }
+
     let mut stmt = db_conn.prepare(
-        r#"INSERT INTO article (filename, title, url, date, is_draft)
-        VALUES (?1, ?2, ?3, ?4, ?5)
+        r#"INSERT INTO article (filename, title, url, date, is_draft, section, lang, page_ref, tags, params)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
         ON CONFLICT(filename) DO UPDATE SET
-            title = excluded.title, url = excluded.url, date = excluded.date
+            title = excluded.title, url = excluded.url, date = excluded.date,
+            is_draft = excluded.is_draft, section = excluded.section,
+            lang = excluded.lang, page_ref = excluded.page_ref,
+            tags = excluded.tags, params = excluded.params
         RETURNING (id)"#,
     )?;
     let id = stmt
         .query_row(
             (
-                filepath.file_name().unwrap().to_string_lossy(),
+                &filename,
                 &article.title,
                 &article.url,
                 article.date,
                 article.is_draft,
+                &article.section,
+                &article.lang,
+                &article.page_ref,
+                serde_json::to_string(&article.tags)?,
+                serde_json::to_string(&article.params)?,
             ),
             |row| row.get::<_, usize>(0),
         )
@@ -361,23 +1035,88 @@ fn gather_file(db_conn: &rusqlite::Connection, filepath: &path::Path) -> anyhow:
         .with_context(|| format!("filename={}", filepath.display()))?;
     let article_id = id.unwrap();
 
-    // If the chunk text hasn't changed skip it
-    let mut exists_stmt = db_conn
-        .prepare("SELECT chunk_id FROM article_chunk WHERE article_id = ?1 AND text = ?2")?;
-    let mut chunk_stmt = db_conn
-        .prepare("INSERT INTO article_chunk (article_id, chunk_id, text) VALUES (?1, ?2, ?3) ON CONFLICT(article_id, chunk_id) DO UPDATE SET text = excluded.text")?;
+    // chunk_id -> (text_hash, text). Older versions didn't store the hash.
+    let mut existing: HashMap<usize, (Option<String>, String)> = HashMap::new();
+    let mut existing_stmt = db_conn
+        .prepare("SELECT chunk_id, text_hash, text FROM article_chunk WHERE article_id = ?1")?;
+    let rows = existing_stmt.query_map([article_id], |row| {
+        Ok((row.get(0)?, (row.get(1)?, row.get(2)?)))
+    })?;
+    for row in rows {
+        let (chunk_id, v) = row?;
+        existing.insert(chunk_id, v);
+    }
+
+    let mut g = Gathered {
+        is_new: existing.is_empty(),
+        renamed_from,
+        changed_chunks: 0,
+        added_chunks: 0,
+        removed_chunks: 0,
+    };
+    // A changed chunk needs a new embedding
+    let mut update_stmt = db_conn.prepare(
+        r#"UPDATE article_chunk
+        SET text = ?3, text_hash = ?4, embed = NULL, embed_model = NULL, embed_dim = NULL,
+            embed_dim_requested = NULL
+        WHERE article_id = ?1 AND chunk_id = ?2"#,
+    )?;
+    let mut insert_stmt = db_conn.prepare(
+        "INSERT INTO article_chunk (article_id, chunk_id, text, text_hash) VALUES (?1, ?2, ?3, ?4)",
+    )?;
     for (idx, c) in article.chunks.iter().enumerate() {
-        let maybe_chunk_id = exists_stmt
-            .query_row((article_id, c), |row| row.get::<_, usize>(0))
-            .optional()?;
-        if maybe_chunk_id.is_none() {
-            chunk_stmt.execute((article_id, idx, c))?;
+        let hash = text_hash(c);
+        match existing.get(&idx) {
+            Some((Some(h), _)) if *h == hash => {}
+            Some((None, text)) if text == c => {
+                db_conn.execute(
+                    "UPDATE article_chunk SET text_hash = ?3 WHERE article_id = ?1 AND chunk_id = ?2",
+                    (article_id, idx, &hash),
+                )?;
+            }
+            Some(_) => {
+                update_stmt.execute((article_id, idx, c, &hash))?;
+                g.changed_chunks += 1;
+            }
+            None => {
+                insert_stmt.execute((article_id, idx, c, &hash))?;
+                g.added_chunks += 1;
+            }
         }
     }
-    Ok(article)
+    g.removed_chunks = db_conn.execute(
+        "DELETE FROM article_chunk WHERE article_id = ?1 AND chunk_id >= ?2",
+        (article_id, article.chunks.len()),
+    )?;
+
+    if !g.is_new && g.changed_chunks + g.added_chunks + g.removed_chunks > 0 {
+        // Scores against the old text are wrong now
+        db_conn.execute(
+            "DELETE FROM article_similarity WHERE article_a = ?1 OR article_b = ?1",
+            [article_id],
+        )?;
+        db::mark_changed(db_conn, article_id)?;
+    } else if was_draft.is_some_and(|d| d != article.is_draft) {
+        // Calc skips drafts, so a post just published has no scores yet
+        db::mark_changed(db_conn, article_id)?;
+    }
+    Ok(g)
+}
+
+// Identifies an article's whole text, whatever its file is called
+fn article_hash<S: AsRef<str>>(chunks: &[S]) -> String {
+    let hashes: Vec<String> = chunks.iter().map(|c| text_hash(c.as_ref())).collect();
+    text_hash(&hashes.join("\n"))
+}
+
+fn text_hash(s: &str) -> String {
+    sha2::Sha256::digest(s.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
 }
 
-fn f64_vec_to_u8_vec(vec: Vec<f64>) -> Vec<u8> {
+pub fn f64_vec_to_u8_vec(vec: Vec<f64>) -> Vec<u8> {
     let mut u8_vec: Vec<u8> = Vec::with_capacity(vec.len() * std::mem::size_of::<f64>());
     for num in vec {
         u8_vec.extend_from_slice(&num.to_ne_bytes());
@@ -385,7 +1124,7 @@ fn f64_vec_to_u8_vec(vec: Vec<f64>) -> Vec<u8> {
     u8_vec
 }
 
-fn u8_vec_to_f64_vec(vec: Vec<u8>) -> Vec<f64> {
+pub fn u8_vec_to_f64_vec(vec: Vec<u8>) -> Vec<f64> {
     assert_eq!(vec.len() % std::mem::size_of::<f64>(), 0);
     let mut f64_vec: Vec<f64> = Vec::with_capacity(vec.len() / std::mem::size_of::<f64>());
     for chunk in vec.chunks_exact(std::mem::size_of::<f64>()) {
@@ -395,18 +1134,6 @@ fn u8_vec_to_f64_vec(vec: Vec<u8>) -> Vec<f64> {
     f64_vec
 }
 
-fn do_fixup(_db_path: &str) -> anyhow::Result<()> {
-    let dir = "/home/graham/src/darkcoding/content/posts";
-    for entry in fs::read_dir(dir)? {
-        let filepath = entry?.path();
-        let s = fs::read_to_string(&filepath)?;
-        let a = Article::parse(&filepath, &s)?;
-        println!("{a:?}");
-    }
-
-    Ok(())
-}
-
 #[repr(C)]
 struct Winsize {
     ws_row: u16,
@@ -418,9 +1145,468 @@ struct Winsize {
 fn get_terminal_width() -> usize {
     let mut winsize: Winsize = unsafe { std::mem::zeroed() };
     let fd = 0; // standard input
-    if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut winsize) } == -1 {
-        eprintln!("Error getting terminal size");
-        std::process::exit(1);
+    if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut winsize) } == -1 || winsize.ws_col == 0 {
+        // Not a terminal, e.g. running from cron
+        return 80;
     }
     winsize.ws_col as usize
 }
+
+// Overwrite the current line with `[label    done / total]`
+fn show_progress(
+    out: &mut impl Write,
+    width: usize,
+    label: &str,
+    done: usize,
+    total: usize,
+) -> io::Result<()> {
+    let progress = format!("{done} / {total}");
+    let label_width = width.saturating_sub(progress.len() + 3);
+    let label: String = label.chars().take(label_width).collect();
+    let spaces = " ".repeat(width.saturating_sub(label.chars().count() + progress.len() + 2));
+    write!(out, "\r[{label}{spaces}{progress}]")?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Fake {
+        dimensions: Option<usize>,
+        // Calls start at 1. This one returns an error.
+        fail_on: Option<usize>,
+        calls: AtomicUsize,
+        running: AtomicUsize,
+        most_running: AtomicUsize,
+        batch_sizes: Mutex<Vec<usize>>,
+    }
+
+    impl embedder::Embedder for Fake {
+        fn model(&self) -> &str {
+            "text-embedding-3-small"
+        }
+        fn dimensions(&self) -> Option<usize> {
+            self.dimensions
+        }
+        fn embed(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f64>>> {
+            let call = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_running.fetch_max(running, Ordering::SeqCst);
+            // Long enough for every worker to pick up a batch
+            thread::sleep(Duration::from_millis(50));
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            if Some(call) == self.fail_on {
+                anyhow::bail!("batch {call} failed");
+            }
+            self.batch_sizes.lock().unwrap().push(texts.len());
+            Ok(texts.iter().map(|_| vec![0.5; 8]).collect())
+        }
+    }
+
+    // `chunks` chunks spread over articles of 10 chunks each
+    fn db_with_chunks(chunks: usize) -> rusqlite::Connection {
+        let db_conn = db::open_in_memory().unwrap();
+        for article_id in 0..chunks.div_ceil(10) {
+            db_conn
+                .execute(
+                    "INSERT INTO article (id, title, url, filename, is_draft) VALUES (?1, 'T', '', ?2, 0)",
+                    (article_id + 1, format!("{article_id}.md")),
+                )
+                .unwrap();
+        }
+        for i in 0..chunks {
+            db_conn
+                .execute(
+                    "INSERT INTO article_chunk (article_id, chunk_id, text) VALUES (?1, ?2, 'text')",
+                    (i / 10 + 1, i % 10),
+                )
+                .unwrap();
+        }
+        db_conn
+    }
+
+    fn count_embedded(db_conn: &rusqlite::Connection) -> usize {
+        db_conn
+            .query_row(
+                "SELECT COUNT(*) FROM article_chunk WHERE embed IS NOT NULL",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn embeds_in_batches_on_workers() {
+        let mut db_conn = db_with_chunks(500);
+        let fake = Fake::default();
+        embed_all(&mut db_conn, &fake, &embedder::Settings::default(), false).unwrap();
+
+        assert_eq!(count_embedded(&db_conn), 500);
+        let mut sizes = fake.batch_sizes.into_inner().unwrap();
+        sizes.sort();
+        assert_eq!(sizes, [52, 64, 64, 64, 64, 64, 64, 64]);
+        assert_eq!(fake.most_running.into_inner(), 4);
+    }
+
+    #[test]
+    fn failed_batch_keeps_earlier_ones() {
+        let mut db_conn = db_with_chunks(1000);
+        let fake = Fake {
+            fail_on: Some(6),
+            ..Default::default()
+        };
+        let err =
+            embed_all(&mut db_conn, &fake, &embedder::Settings::default(), false).unwrap_err();
+        assert_eq!(err.to_string(), "batch 6 failed");
+
+        // Everything that came back before the queue was emptied is saved
+        let sizes = fake.batch_sizes.into_inner().unwrap();
+        assert!(sizes.len() >= 5);
+        assert!(sizes.len() < 1000 / 64);
+        let saved = sizes.iter().sum::<usize>();
+        assert_eq!(count_embedded(&db_conn), saved);
+
+        // and isn't asked for again
+        let fake = Fake::default();
+        embed_all(&mut db_conn, &fake, &embedder::Settings::default(), false).unwrap();
+        assert_eq!(count_embedded(&db_conn), 1000);
+        assert_eq!(
+            fake.batch_sizes.into_inner().unwrap().iter().sum::<usize>(),
+            1000 - saved
+        );
+    }
+
+    #[test]
+    fn dropping_dimensions_is_a_mismatch() {
+        let db_conn = db::open_in_memory().unwrap();
+        db_conn
+            .execute(
+                "INSERT INTO article (id, title, url, filename, is_draft) VALUES (1, 'Ü', '', 'a.md', 0)",
+                (),
+            )
+            .unwrap();
+        // Embedded by a run with --dimensions 512
+        db_conn
+            .execute(
+                r#"INSERT INTO article_chunk
+                (article_id, chunk_id, text, embed, embed_model, embed_dim, embed_dim_requested)
+                VALUES (1, 0, 'text', x'00', 'text-embedding-3-small', 512, 512)"#,
+                (),
+            )
+            .unwrap();
+        let with_512 = Fake {
+            dimensions: Some(512),
+            ..Default::default()
+        };
+        assert_eq!(count_mismatched_chunks(&db_conn, &with_512).unwrap(), 0);
+        assert!(load_unembedded_chunks(&db_conn, 1, &with_512)
+            .unwrap()
+            .is_empty());
+
+        let without = Fake::default();
+        assert_eq!(count_mismatched_chunks(&db_conn, &without).unwrap(), 1);
+        assert_eq!(
+            load_unembedded_chunks(&db_conn, 1, &without).unwrap(),
+            vec![(0, "text".to_string())]
+        );
+        let with_256 = Fake {
+            dimensions: Some(256),
+            ..Default::default()
+        };
+        assert_eq!(count_mismatched_chunks(&db_conn, &with_256).unwrap(), 1);
+    }
+
+    fn write_post(dir: &path::Path, name: &str, body: &str) {
+        let post = format!("---\ntitle: Post\ndate: 2024-01-02\n---\n{body}");
+        fs::write(dir.join(name), post).unwrap();
+    }
+
+    // Pretend `embed` ran
+    fn embed_everything(db_conn: &rusqlite::Connection) {
+        db_conn
+            .execute(
+                "UPDATE article_chunk SET embed = zeroblob(8), embed_model = 'm', embed_dim = 1 WHERE embed IS NULL",
+                (),
+            )
+            .unwrap();
+    }
+
+    // Whether each of the article's chunks has an embedding, in order
+    fn embedded(db_conn: &rusqlite::Connection, filename: &str) -> Vec<bool> {
+        let mut stmt = db_conn
+            .prepare(
+                r#"SELECT c.embed IS NOT NULL FROM article_chunk c
+                JOIN article a ON a.id = c.article_id
+                WHERE a.filename = ?1 ORDER BY c.chunk_id"#,
+            )
+            .unwrap();
+        let rows = stmt.query_map([filename], |row| row.get(0)).unwrap();
+        rows.collect::<Result<_, _>>().unwrap()
+    }
+
+    fn article_id(db_conn: &rusqlite::Connection, filename: &str) -> Option<usize> {
+        db_conn
+            .query_row(
+                "SELECT id FROM article WHERE filename = ?1",
+                [filename],
+                |row| row.get(0),
+            )
+            .optional()
+            .unwrap()
+    }
+
+    fn count(db_conn: &rusqlite::Connection, table: &str) -> usize {
+        db_conn
+            .query_row(&format!("SELECT COUNT(*) FROM {table}"), (), |row| {
+                row.get(0)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn gather_keeps_what_did_not_change() {
+        let dir = std::env::temp_dir().join(format!("hugo-ai-gather-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+        let filter = content::Filter::default();
+        let chunking = chunk::Settings {
+            max_tokens: Some(50),
+            overlap: None,
+        };
+        let normalize = normalize::Settings::default();
+        let mut db_conn = db::open_in_memory().unwrap();
+
+        // Three chunks, one per paragraph
+        let para = |word: &str| vec![word; 40].join(" ");
+        let long = format!("{}\n\n{}", para("one"), para("two"));
+        write_post(&dir, "a.md", &format!("{long}\n\n{}", para("six")));
+        write_post(&dir, "b.md", "Short");
+        gather(
+            &mut db_conn,
+            ":memory:",
+            dir_str,
+            &filter,
+            &chunking,
+            &normalize,
+        )
+        .unwrap();
+        embed_everything(&db_conn);
+        let (a, b) = (
+            article_id(&db_conn, "a.md").unwrap(),
+            article_id(&db_conn, "b.md").unwrap(),
+        );
+        db_conn
+            .execute(
+                "INSERT INTO article_similarity (article_a, article_b, strategy, similarity) VALUES (?1, ?2, 'mean-pairs', 0.9)",
+                (a, b),
+            )
+            .unwrap();
+
+        // Unchanged
+        gather(
+            &mut db_conn,
+            ":memory:",
+            dir_str,
+            &filter,
+            &chunking,
+            &normalize,
+        )
+        .unwrap();
+        assert_eq!(embedded(&db_conn, "a.md"), [true, true, true]);
+        assert_eq!(count(&db_conn, "article_similarity"), 1);
+
+        // Edited chunk
+        write_post(&dir, "a.md", &format!("{long}\n\n{} ten", para("six")));
+        gather(
+            &mut db_conn,
+            ":memory:",
+            dir_str,
+            &filter,
+            &chunking,
+            &normalize,
+        )
+        .unwrap();
+        assert_eq!(embedded(&db_conn, "a.md"), [true, true, false]);
+        assert_eq!(count(&db_conn, "article_similarity"), 0);
+
+        // Shortened post
+        embed_everything(&db_conn);
+        write_post(&dir, "a.md", &para("one"));
+        gather(
+            &mut db_conn,
+            ":memory:",
+            dir_str,
+            &filter,
+            &chunking,
+            &normalize,
+        )
+        .unwrap();
+        assert_eq!(embedded(&db_conn, "a.md"), [true]);
+        assert_eq!(article_id(&db_conn, "a.md"), Some(a));
+
+        // Renamed file
+        embed_everything(&db_conn);
+        fs::rename(dir.join("b.md"), dir.join("c.md")).unwrap();
+        gather(
+            &mut db_conn,
+            ":memory:",
+            dir_str,
+            &filter,
+            &chunking,
+            &normalize,
+        )
+        .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(article_id(&db_conn, "b.md"), None);
+        assert_eq!(article_id(&db_conn, "c.md"), Some(b));
+        assert_eq!(embedded(&db_conn, "c.md"), [true]);
+        assert_eq!(count(&db_conn, "article"), 2);
+    }
+
+    #[test]
+    fn prune_removes_missing() {
+        let dir = std::env::temp_dir().join(format!("hugo-ai-prune-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+        let mut db_conn = db::open_in_memory().unwrap();
+        write_post(&dir, "a.md", "First");
+        write_post(&dir, "b.md", "Second");
+        gather(
+            &mut db_conn,
+            ":memory:",
+            dir_str,
+            &content::Filter::default(),
+            &chunk::Settings::default(),
+            &normalize::Settings::default(),
+        )
+        .unwrap();
+        let b = article_id(&db_conn, "b.md").unwrap();
+
+        fs::remove_file(dir.join("b.md")).unwrap();
+        assert_eq!(
+            missing_articles(&db_conn, &dir).unwrap(),
+            [(b, "b.md".to_string())]
+        );
+        prune(&mut db_conn, ":memory:", dir_str, true).unwrap();
+        assert_eq!(count(&db_conn, "article"), 2);
+        prune(&mut db_conn, ":memory:", dir_str, false).unwrap();
+        assert_eq!(count(&db_conn, "article"), 1);
+        assert_eq!(count(&db_conn, "article_chunk"), 1);
+        assert!(missing_articles(&db_conn, &dir).unwrap().is_empty());
+
+        // Every article missing is more likely the wrong directory
+        fs::remove_file(dir.join("a.md")).unwrap();
+        let res = prune(&mut db_conn, ":memory:", dir_str, false);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(res.is_err());
+        assert_eq!(count(&db_conn, "article"), 1);
+    }
+
+    #[test]
+    fn article_hashes() {
+        let h = article_hash(&["one", "two"]);
+        assert_eq!(h, article_hash(&["one".to_string(), "two".to_string()]));
+        assert_ne!(h, article_hash(&["one", "three"]));
+        assert_ne!(h, article_hash(&["two", "one"]));
+        assert_ne!(h, article_hash(&["one"]));
+        assert_ne!(h, article_hash(&["onetwo"]));
+    }
+
+    // Give the article's only chunk a new embedding, as `embed` would
+    fn reembed(db_conn: &mut rusqlite::Connection, article_id: usize, embed: Vec<f64>) {
+        let batch = [ChunkToEmbed {
+            article_id,
+            chunk_id: 0,
+            text: "text".to_string(),
+        }];
+        save_embeddings(db_conn, &Fake::default(), &batch, vec![embed]).unwrap();
+    }
+
+    // (article_a, article_b, similarity to two places) calculated by `strategy`
+    fn scores(db_conn: &rusqlite::Connection, strategy: &str) -> Vec<(usize, usize, f64)> {
+        let mut stmt = db_conn
+            .prepare(
+                r#"SELECT article_a, article_b, ROUND(similarity, 2) FROM article_similarity
+                WHERE strategy = ?1 ORDER BY 1, 2"#,
+            )
+            .unwrap();
+        let rows = stmt
+            .query_map([strategy], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .unwrap();
+        rows.collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn calc_only_changed_pairs() {
+        let mean = || strategy::Scoring::default();
+        let max = || strategy::Scoring::new(strategy::Strategy::MaxPair, 3);
+        let mut db_conn = db::open_in_memory().unwrap();
+        for id in 1..=3 {
+            db_conn
+                .execute(
+                    "INSERT INTO article (id, title, url, filename, is_draft) VALUES (?1, 'T', '', ?2, 0)",
+                    (id, format!("{id}.md")),
+                )
+                .unwrap();
+            db_conn
+                .execute(
+                    "INSERT INTO article_chunk (article_id, chunk_id, text) VALUES (?1, 0, 'text')",
+                    [id],
+                )
+                .unwrap();
+        }
+        reembed(&mut db_conn, 1, vec![1.0, 0.0]);
+        reembed(&mut db_conn, 2, vec![0.6, 0.8]);
+        reembed(&mut db_conn, 3, vec![0.0, 1.0]);
+        assert_eq!(calc(&mut db_conn, mean(), false).unwrap(), 3);
+        assert_eq!(calc(&mut db_conn, max(), false).unwrap(), 3);
+        let version = Some(db::embed_version(&db_conn).unwrap().to_string());
+        assert_eq!(
+            db::get_meta(&db_conn, "calc_version:mean-pairs").unwrap(),
+            version
+        );
+        assert_eq!(
+            db::get_meta(&db_conn, "calc_version:max-pair").unwrap(),
+            version
+        );
+        assert_eq!(calc(&mut db_conn, mean(), false).unwrap(), 0);
+
+        // Spoil the scores, to see which get calculated again
+        db_conn
+            .execute("UPDATE article_similarity SET similarity = -1", ())
+            .unwrap();
+        reembed(&mut db_conn, 2, vec![0.8, 0.6]);
+        assert_eq!(calc(&mut db_conn, mean(), false).unwrap(), 2);
+        assert_eq!(
+            scores(&db_conn, "mean-pairs"),
+            [(1, 2, 0.8), (1, 3, -1.0), (2, 3, 0.6)]
+        );
+        // Another strategy's scores wait for a calc with that strategy
+        assert_eq!(
+            scores(&db_conn, "max-pair"),
+            [(1, 2, -1.0), (1, 3, -1.0), (2, 3, -1.0)]
+        );
+        assert_eq!(calc(&mut db_conn, max(), false).unwrap(), 2);
+        assert_eq!(
+            scores(&db_conn, "max-pair"),
+            [(1, 2, 0.8), (1, 3, -1.0), (2, 3, 0.6)]
+        );
+
+        // Both changed, so their pair is only scored once
+        reembed(&mut db_conn, 1, vec![0.0, 1.0]);
+        reembed(&mut db_conn, 3, vec![1.0, 0.0]);
+        assert_eq!(calc(&mut db_conn, mean(), false).unwrap(), 3);
+        assert_eq!(calc(&mut db_conn, mean(), true).unwrap(), 3);
+        assert_eq!(
+            scores(&db_conn, "mean-pairs"),
+            [(1, 2, 0.6), (1, 3, 0.0), (2, 3, 0.8)]
+        );
+    }
+}
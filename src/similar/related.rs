@@ -0,0 +1,353 @@
+// MIT License
+// Copyright (c) 2024 Graham King
+
+use std::collections::HashMap;
+
+use crate::article::Article;
+
+const DEFAULT_COUNT: usize = 3;
+const DEFAULT_MIN_SIMILARITY: f64 = 0.4;
+
+/// How many related posts `write` picks, and how similar they must be.
+/// The `[related]` section of the config file, with `[related.sections.<name>]`
+/// for sections that want something different. Command line flags take
+/// priority over both.
+#[derive(clap::Args, serde::Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// How many related posts to write [default: 3]
+    #[clap(long)]
+    pub count: Option<usize>,
+
+    /// Leave out posts less similar than this, between 0 and 1 [default: 0.4]
+    #[clap(long)]
+    pub min_similarity: Option<f64>,
+
+    /// Write at least this many, even if they are less similar than
+    /// --min-similarity [default: 0]
+    #[clap(long)]
+    pub min_count: Option<usize>,
+
+    /// Also write each related post's score, in a `related_scores` field
+    #[clap(long, num_args = 0..=1, default_missing_value = "true")]
+    pub scores: Option<bool>,
+
+    /// Re-rank for variety with Maximal Marginal Relevance. 1 is most similar
+    /// first as usual, lower values prefer posts unlike the ones already picked.
+    /// Try 0.7.
+    #[clap(long, value_name = "LAMBDA")]
+    pub mmr_lambda: Option<f64>,
+
+    /// Only recommend posts older than this one
+    #[clap(long, num_args = 0..=1, default_missing_value = "true")]
+    pub older_only: Option<bool>,
+
+    /// Only recommend posts in the same language
+    #[clap(long, num_args = 0..=1, default_missing_value = "true")]
+    pub same_lang: Option<bool>,
+
+    /// Only recommend posts in the same section
+    #[clap(long, num_args = 0..=1, default_missing_value = "true")]
+    pub same_section: Option<bool>,
+
+    /// Only recommend posts that share at least one tag with this one
+    #[clap(long, num_args = 0..=1, default_missing_value = "true")]
+    pub shared_tag: Option<bool>,
+
+    /// Never recommend posts whose front matter has one of these values,
+    /// e.g. `exclude = { noindex = true }`
+    #[clap(skip)]
+    pub exclude: Option<HashMap<String, serde_json::Value>>,
+
+    /// Overrides for the posts in a section, by section name
+    #[clap(skip)]
+    pub sections: HashMap<String, Settings>,
+}
+
+impl Settings {
+    /// Fill in anything not set here from `other`
+    pub fn or(self, other: &Settings) -> Settings {
+        Settings {
+            count: self.count.or(other.count),
+            min_similarity: self.min_similarity.or(other.min_similarity),
+            min_count: self.min_count.or(other.min_count),
+            scores: self.scores.or(other.scores),
+            mmr_lambda: self.mmr_lambda.or(other.mmr_lambda),
+            older_only: self.older_only.or(other.older_only),
+            same_lang: self.same_lang.or(other.same_lang),
+            same_section: self.same_section.or(other.same_section),
+            shared_tag: self.shared_tag.or(other.shared_tag),
+            exclude: self.exclude.or_else(|| other.exclude.clone()),
+            sections: HashMap::new(),
+        }
+    }
+
+    /// The settings for a post in `section`: these, then that section's
+    /// config, then the rest of `config`
+    pub fn for_section(&self, config: &Settings, section: &str) -> Settings {
+        match config.sections.get(section) {
+            Some(s) => self.clone().or(s).or(config),
+            None => self.clone().or(config),
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.unwrap_or(DEFAULT_COUNT)
+    }
+
+    pub fn min_similarity(&self) -> f64 {
+        self.min_similarity.unwrap_or(DEFAULT_MIN_SIMILARITY)
+    }
+
+    pub fn min_count(&self) -> usize {
+        self.min_count.unwrap_or(0).min(self.count())
+    }
+
+    pub fn scores(&self) -> bool {
+        self.scores.unwrap_or(false)
+    }
+
+    /// Can `candidate` be recommended on `post`
+    pub fn allows(&self, post: &Article, candidate: &Article) -> bool {
+        if self.older_only.unwrap_or(false) {
+            if let (Some(post_date), Some(date)) = (post.date, candidate.date) {
+                if date >= post_date {
+                    return false;
+                }
+            }
+        }
+        if self.same_lang.unwrap_or(false) && candidate.lang != post.lang {
+            return false;
+        }
+        if self.same_section.unwrap_or(false) && candidate.section != post.section {
+            return false;
+        }
+        if self.shared_tag.unwrap_or(false)
+            && !post
+                .tags
+                .iter()
+                .any(|t| candidate.tags.iter().any(|c| c.eq_ignore_ascii_case(t)))
+        {
+            return false;
+        }
+        // Hugo front matter keys are case insensitive
+        let is_excluded = self.exclude.iter().flatten().any(|(key, value)| {
+            candidate
+                .params
+                .iter()
+                .any(|(k, v)| k.eq_ignore_ascii_case(key) && v == value)
+        });
+        !is_excluded
+    }
+
+    /// Choose from `candidates`, which are sorted most similar first.
+    /// `similarity` compares two candidates, for MMR.
+    pub fn pick<T>(
+        &self,
+        candidates: Vec<(T, f64)>,
+        similarity: impl Fn(&T, &T) -> f64,
+    ) -> Vec<(T, f64)> {
+        let mut pool: Vec<(T, f64)> = candidates
+            .into_iter()
+            .enumerate()
+            .take_while(|(idx, (_, score))| {
+                *score >= self.min_similarity() || *idx < self.min_count()
+            })
+            .map(|(_, c)| c)
+            .collect();
+        let Some(lambda) = self.mmr_lambda.map(|l| l.clamp(0.0, 1.0)) else {
+            pool.truncate(self.count());
+            return pool;
+        };
+
+        // Each pick is the candidate with the best balance of being similar to
+        // this post and unlike the closest of the ones picked so far
+        let mut picked: Vec<(T, f64)> = Vec::with_capacity(self.count());
+        while picked.len() < self.count() && !pool.is_empty() {
+            let mmr = |(candidate, score): &(T, f64)| {
+                let redundancy = picked
+                    .iter()
+                    .map(|(p, _)| similarity(candidate, p))
+                    .fold(0.0, f64::max);
+                lambda * score - (1.0 - lambda) * redundancy
+            };
+            let best = pool
+                .iter()
+                .enumerate()
+                .max_by(|(_, a), (_, b)| mmr(a).total_cmp(&mmr(b)))
+                .map(|(idx, _)| idx)
+                .unwrap_or_default();
+            picked.push(pool.remove(best));
+        }
+        picked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn article(section: &str, date: Option<&str>, tags: &[&str]) -> Article {
+        Article {
+            id: 0,
+            title: String::new(),
+            url: String::new(),
+            date: date.map(|d| chrono::DateTime::parse_from_rfc3339(d).unwrap()),
+            filename: Default::default(),
+            section: section.to_string(),
+            lang: "en".to_string(),
+            page_ref: String::new(),
+            is_draft: false,
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            params: Default::default(),
+            chunks: vec![],
+        }
+    }
+
+    fn names(picked: Vec<(&str, f64)>) -> Vec<&str> {
+        picked.into_iter().map(|(name, _)| name).collect()
+    }
+
+    #[test]
+    fn pick_by_similarity() {
+        let candidates = vec![("a", 0.9), ("b", 0.8), ("c", 0.35), ("d", 0.3)];
+        let never = |_: &&str, _: &&str| 0.0;
+        let picked = Settings::default().pick(candidates.clone(), never);
+        assert_eq!(picked, vec![("a", 0.9), ("b", 0.8)]);
+
+        let s = Settings {
+            min_count: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(names(s.pick(candidates.clone(), never)), ["a", "b", "c"]);
+        let s = Settings {
+            count: Some(1),
+            min_count: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(names(s.pick(candidates.clone(), never)), ["a"]);
+        let s = Settings {
+            min_similarity: Some(0.0),
+            count: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(names(s.pick(candidates, never)), ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn pick_mmr() {
+        // b is nearly the same post as a, c is about something else
+        let candidates = vec![("a", 0.9), ("b", 0.88), ("c", 0.7)];
+        let similarity = |x: &&str, y: &&str| match (*x, *y) {
+            ("a", "b") | ("b", "a") => 0.95,
+            _ => 0.1,
+        };
+        let with_lambda = |lambda| Settings {
+            count: Some(2),
+            mmr_lambda: Some(lambda),
+            ..Default::default()
+        };
+        assert_eq!(
+            with_lambda(0.7).pick(candidates.clone(), similarity),
+            vec![("a", 0.9), ("c", 0.7)]
+        );
+        // 1 is plain most similar first, and anything above is treated as 1
+        assert_eq!(
+            names(with_lambda(1.0).pick(candidates.clone(), similarity)),
+            ["a", "b"]
+        );
+        assert_eq!(
+            names(with_lambda(5.0).pick(candidates.clone(), similarity)),
+            ["a", "b"]
+        );
+        // Still only from those similar enough
+        let s = Settings {
+            min_similarity: Some(0.8),
+            ..with_lambda(0.5)
+        };
+        assert_eq!(names(s.pick(candidates, similarity)), ["a", "b"]);
+    }
+
+    #[test]
+    fn allows() {
+        let post = article("posts", Some("2024-03-01T00:00:00Z"), &["Rust", "café"]);
+        let older = article("posts", Some("2023-03-01T00:00:00+01:00"), &["go"]);
+        let newer = article("notes", Some("2024-06-01T00:00:00Z"), &["rust"]);
+        let undated = article("notes", None, &["CAFÉ"]);
+
+        let any = Settings::default();
+        for c in [&older, &newer, &undated] {
+            assert!(any.allows(&post, c));
+        }
+
+        let older_only = Settings {
+            older_only: Some(true),
+            ..Default::default()
+        };
+        assert!(older_only.allows(&post, &older));
+        assert!(!older_only.allows(&post, &newer));
+        assert!(!older_only.allows(&post, &post), "same date isn't older");
+        // Without a date we can't tell
+        assert!(older_only.allows(&post, &undated));
+
+        let same_section = Settings {
+            same_section: Some(true),
+            ..Default::default()
+        };
+        assert!(same_section.allows(&post, &older));
+        assert!(!same_section.allows(&post, &newer));
+
+        let shared_tag = Settings {
+            shared_tag: Some(true),
+            ..Default::default()
+        };
+        assert!(!shared_tag.allows(&post, &older));
+        assert!(shared_tag.allows(&post, &newer));
+
+        let mut noindex = older;
+        noindex
+            .params
+            .insert("noIndex".to_string(), serde_json::json!(true));
+        let exclude = Settings {
+            exclude: Some([("noindex".to_string(), serde_json::json!(true))].into()),
+            ..Default::default()
+        };
+        assert!(!exclude.allows(&post, &noindex));
+        assert!(exclude.allows(&post, &newer));
+    }
+
+    #[test]
+    fn for_section() {
+        let config: Settings = toml::from_str(
+            r#"
+            count = 3
+            min_similarity = 0.5
+            [sections.notes]
+            count = 5
+            same_section = true
+            "#,
+        )
+        .unwrap();
+        let cli = Settings::default();
+
+        let notes = cli.for_section(&config, "notes");
+        assert_eq!(notes.count(), 5);
+        assert_eq!(notes.min_similarity(), 0.5);
+        assert_eq!(notes.same_section, Some(true));
+
+        let posts = cli.for_section(&config, "posts");
+        assert_eq!(posts.count(), 3);
+        assert_eq!(posts.same_section, None);
+
+        // The command line beats both
+        let cli = Settings {
+            count: Some(1),
+            same_section: Some(false),
+            ..Default::default()
+        };
+        let notes = cli.for_section(&config, "notes");
+        assert_eq!(notes.count(), 1);
+        assert_eq!(notes.same_section, Some(false));
+        assert_eq!(notes.min_similarity(), 0.5);
+    }
+}
@@ -0,0 +1,231 @@
+// MIT License
+// Copyright (c) 2024 Graham King
+
+/// How to turn the similarity of two articles' chunks into one score
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum Strategy {
+    /// Mean cosine similarity of every pair of chunks. Long posts score lower.
+    #[default]
+    MeanPairs,
+    /// Average each article's chunk embeddings into one vector, compare those
+    Centroid,
+    /// The most similar pair of chunks
+    MaxPair,
+    /// Mean of the --top-k most similar pairs of chunks
+    TopK,
+    /// Like mean-pairs, but longer chunks count for more
+    LengthWeighted,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// How to combine chunk similarities into an article similarity
+    #[clap(long, value_enum)]
+    strategy: Option<Strategy>,
+    /// How many chunk pairs the top-k strategy averages
+    #[clap(long, default_value_t = 3)]
+    top_k: usize,
+}
+
+impl Args {
+    /// The strategy given on the command line, if any
+    pub fn scoring(&self) -> Option<Scoring> {
+        self.strategy.map(|strategy| Scoring {
+            strategy,
+            top_k: self.top_k.max(1),
+        })
+    }
+}
+
+pub struct Scoring {
+    strategy: Strategy,
+    top_k: usize,
+}
+
+impl Default for Scoring {
+    fn default() -> Self {
+        Scoring {
+            strategy: Strategy::default(),
+            top_k: 3,
+        }
+    }
+}
+
+impl Scoring {
+    #[cfg(test)]
+    pub fn new(strategy: Strategy, top_k: usize) -> Scoring {
+        Scoring { strategy, top_k }
+    }
+
+    /// What we store in the database next to the scores, e.g. "centroid" or "top-3"
+    pub fn key(&self) -> String {
+        match self.strategy {
+            Strategy::MeanPairs => "mean-pairs".to_string(),
+            Strategy::Centroid => "centroid".to_string(),
+            Strategy::MaxPair => "max-pair".to_string(),
+            Strategy::TopK => format!("top-{}", self.top_k),
+            Strategy::LengthWeighted => "length-weighted".to_string(),
+        }
+    }
+
+    /// Errors if the articles' embeddings are of different lengths
+    pub fn score(&self, a: &Embedded, b: &Embedded) -> anyhow::Result<f64> {
+        if a.dim() != b.dim() {
+            anyhow::bail!(
+                "Can't compare embeddings of length {} and {}",
+                a.dim(),
+                b.dim()
+            );
+        }
+        let score = match self.strategy {
+            Strategy::Centroid => dot(&a.centroid, &b.centroid),
+            Strategy::MeanPairs => {
+                let sims = pair_similarities(a, b);
+                sims.iter().sum::<f64>() / sims.len() as f64
+            }
+            Strategy::MaxPair => pair_similarities(a, b)
+                .into_iter()
+                .fold(f64::NEG_INFINITY, f64::max),
+            Strategy::TopK => {
+                let mut sims = pair_similarities(a, b);
+                sims.sort_by(|x, y| y.total_cmp(x));
+                sims.truncate(self.top_k);
+                sims.iter().sum::<f64>() / sims.len() as f64
+            }
+            Strategy::LengthWeighted => {
+                let mut sum = 0.0;
+                let mut total_weight = 0.0;
+                for (a_embed, a_weight) in a.chunks.iter().zip(a.weights.iter()) {
+                    for (b_embed, b_weight) in b.chunks.iter().zip(b.weights.iter()) {
+                        sum += a_weight * b_weight * dot(a_embed, b_embed);
+                        total_weight += a_weight * b_weight;
+                    }
+                }
+                sum / total_weight
+            }
+        };
+        Ok(score)
+    }
+}
+
+/// An article's chunk embeddings, ready to compare
+pub struct Embedded {
+    // Unit length, so cosine similarity is just the dot product
+    chunks: Vec<Vec<f64>>,
+    // Length of each chunk's text
+    weights: Vec<f64>,
+    // Unit length mean of the chunks
+    centroid: Vec<f64>,
+}
+
+impl Embedded {
+    /// Cosine similarity of the two articles' centroids, or 0 if their
+    /// embeddings are of different lengths
+    pub fn centroid_similarity(&self, other: &Embedded) -> f64 {
+        if self.dim() != other.dim() {
+            return 0.0;
+        }
+        dot(&self.centroid, &other.centroid)
+    }
+
+    /// `chunks` are (embedding, text length) in chunk order. There must be at
+    /// least one, and they must all be the same length.
+    pub fn new(chunks: Vec<(Vec<f64>, usize)>) -> anyhow::Result<Embedded> {
+        let Some((first, _)) = chunks.first() else {
+            anyhow::bail!("An article needs at least one embedded chunk");
+        };
+        let dim = first.len();
+        if let Some((other, _)) = chunks.iter().find(|(embed, _)| embed.len() != dim) {
+            anyhow::bail!(
+                "Chunk embeddings of different lengths, {dim} and {}",
+                other.len()
+            );
+        }
+        let (chunks, weights): (Vec<Vec<f64>>, Vec<f64>) = chunks
+            .into_iter()
+            .map(|(embed, len)| (normalize(embed), len.max(1) as f64))
+            .unzip();
+        let mut centroid = vec![0.0; dim];
+        for c in chunks.iter() {
+            centroid.iter_mut().zip(c).for_each(|(sum, x)| *sum += x);
+        }
+        Ok(Embedded {
+            centroid: normalize(centroid),
+            chunks,
+            weights,
+        })
+    }
+
+    fn dim(&self) -> usize {
+        self.centroid.len()
+    }
+}
+
+fn pair_similarities(a: &Embedded, b: &Embedded) -> Vec<f64> {
+    let mut sims = Vec::with_capacity(a.chunks.len() * b.chunks.len());
+    for a_embed in a.chunks.iter() {
+        for b_embed in b.chunks.iter() {
+            sims.push(dot(a_embed, b_embed));
+        }
+    }
+    sims
+}
+
+// Callers check the lengths match
+pub fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+}
+
+/// Scale to length 1
+pub fn normalize(mut v: Vec<f64>) -> Vec<f64> {
+    let magnitude = v.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if magnitude > 0.0 {
+        v.iter_mut().for_each(|x| *x /= magnitude);
+    }
+    v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn score(strategy: Strategy, top_k: usize, a: &Embedded, b: &Embedded) -> f64 {
+        Scoring { strategy, top_k }.score(a, b).unwrap()
+    }
+
+    #[test]
+    fn known_vectors() {
+        let a = Embedded::new(vec![(vec![1.0, 0.0], 100), (vec![0.0, 1.0], 300)]).unwrap();
+        // Not unit length, (0.6, 0.8) once normalized
+        let b = Embedded::new(vec![(vec![1.0, 0.0], 100), (vec![3.0, 4.0], 100)]).unwrap();
+        // The pairs score 1, 0.6, 0 and 0.8
+        let close = |x: f64, y: f64| (x - y).abs() < 1e-9;
+        assert!(close(score(Strategy::MeanPairs, 3, &a, &b), 0.6));
+        assert!(close(score(Strategy::MaxPair, 3, &a, &b), 1.0));
+        assert!(close(score(Strategy::TopK, 2, &a, &b), 0.9));
+        assert!(close(score(Strategy::TopK, 3, &a, &b), 0.8));
+        assert!(close(score(Strategy::TopK, 10, &a, &b), 0.6));
+        // (1*1*1 + 1*1*0.6 + 3*1*0 + 3*1*0.8) / (1 + 1 + 3 + 3)
+        assert!(close(score(Strategy::LengthWeighted, 3, &a, &b), 0.5));
+        // (1, 1) against (1.6, 0.8)
+        assert!(close(
+            score(Strategy::Centroid, 3, &a, &b),
+            3.0 / 10f64.sqrt()
+        ));
+        assert!(close(score(Strategy::MeanPairs, 3, &a, &a), 0.5));
+        assert!(close(score(Strategy::Centroid, 3, &a, &a), 1.0));
+    }
+
+    #[test]
+    fn bad_embeddings_are_errors() {
+        assert!(Embedded::new(vec![]).is_err());
+        assert!(Embedded::new(vec![(vec![1.0, 0.0], 1), (vec![1.0, 0.0, 0.0], 1)]).is_err());
+
+        let two = Embedded::new(vec![(vec![1.0, 0.0], 1)]).unwrap();
+        let three = Embedded::new(vec![(vec![1.0, 0.0, 0.0], 1)]).unwrap();
+        for strategy in [Strategy::MeanPairs, Strategy::Centroid] {
+            let scoring = Scoring { strategy, top_k: 3 };
+            assert!(scoring.score(&two, &three).is_err());
+        }
+    }
+}
@@ -0,0 +1,734 @@
+// MIT License
+// Copyright (c) 2024 Graham King
+
+use std::collections::HashMap;
+use std::fs;
+use std::path;
+
+use anyhow::Context;
+use serde_json::Value;
+
+use crate::content;
+use crate::front_matter::FrontMatter;
+
+// Where Hugo looks for its configuration, in order
+const CONFIG_FILES: [&str; 14] = [
+    "hugo.toml",
+    "hugo.yaml",
+    "hugo.yml",
+    "hugo.json",
+    "config.toml",
+    "config.yaml",
+    "config.yml",
+    "config.json",
+    "config/_default/hugo.toml",
+    "config/_default/hugo.yaml",
+    "config/_default/hugo.json",
+    "config/_default/config.toml",
+    "config/_default/config.yaml",
+    "config/_default/config.json",
+];
+
+// Where Hugo looks for a config split into one file per key
+const CONFIG_DIR: &str = "config/_default";
+
+/// The directory given on the command line. Either a Hugo site root with a config
+/// file, in which case we know content directories, languages and permalinks,
+/// or a plain directory of Markdown posts.
+#[derive(Debug)]
+pub struct Site {
+    pub root: path::PathBuf,
+    // None for a plain directory of posts
+    config: Option<Config>,
+}
+
+#[derive(Debug)]
+struct Config {
+    base_url: String,
+    default_language: String,
+    default_language_in_subdir: bool,
+    disable_path_to_lower: bool,
+    // Section name -> permalink pattern such as "/:year/:month/:slug/"
+    permalinks: HashMap<String, String>,
+    languages: Vec<Language>,
+    // Relative to root
+    content_dir: path::PathBuf,
+}
+
+#[derive(Debug)]
+struct Language {
+    code: String,
+    // Relative to root. Only set if this language has its own content directory.
+    content_dir: Option<path::PathBuf>,
+    // Overrides of the site permalinks for this language
+    permalinks: HashMap<String, String>,
+}
+
+impl Site {
+    pub fn load(dir: &str) -> anyhow::Result<Site> {
+        let root = path::PathBuf::from(dir);
+        let config_path = CONFIG_FILES
+            .iter()
+            .map(|f| root.join(f))
+            .find(|p| p.is_file());
+        let config_dir = root.join(CONFIG_DIR);
+        if config_path.is_none() && !config_dir.is_dir() {
+            return Ok(Site { root, config: None });
+        }
+        let mut v = match config_path.as_ref() {
+            Some(p) => read_config(p)?,
+            None => Value::Object(Default::default()),
+        };
+        if config_dir.is_dir() {
+            merge_config_dir(&config_dir, config_path.as_deref(), &mut v)?;
+        }
+        let config = Config::from_value(&v).with_context(|| {
+            format!(
+                "{}",
+                config_path.as_deref().unwrap_or(&config_dir).display()
+            )
+        })?;
+        Ok(Site {
+            root,
+            config: Some(config),
+        })
+    }
+
+    pub fn is_hugo_site(&self) -> bool {
+        self.config.is_some()
+    }
+
+    /// All the posts in the site, in every language.
+    /// The filter globs match against the path inside the content directory.
+    pub fn pages(&self, filter: &content::Filter) -> anyhow::Result<Vec<content::Page>> {
+        let Some(cfg) = self.config.as_ref() else {
+            return content::walk(&self.root, filter);
+        };
+        let mut dirs = vec![(cfg.content_dir.clone(), None)];
+        for lang in cfg.languages.iter() {
+            if let Some(d) = lang.content_dir.as_ref() {
+                if !dirs.iter().any(|(existing, _)| existing == d) {
+                    dirs.push((d.clone(), Some(lang.code.clone())));
+                }
+            }
+        }
+
+        let mut pages = Vec::new();
+        for (dir, dir_lang) in dirs.iter() {
+            let full = self.root.join(dir);
+            if !full.is_dir() {
+                continue;
+            }
+            // A language's content dir can be inside the main one, e.g. content/fr.
+            // Its pages belong to that language, not to this walk.
+            let nested: Vec<&path::PathBuf> = dirs
+                .iter()
+                .map(|(d, _)| d)
+                .filter(|d| *d != dir && d.starts_with(dir))
+                .collect();
+            for mut page in content::walk(&full, filter)? {
+                page.rel_path = dir.join(&page.rel_path);
+                if nested.iter().any(|d| page.rel_path.starts_with(d)) {
+                    continue;
+                }
+                page.lang = match dir_lang.as_ref() {
+                    Some(l) => l.clone(),
+                    None => cfg.language_from_filename(&page.content_path),
+                };
+                pages.push(page);
+            }
+        }
+        Ok(pages)
+    }
+
+    /// The site's `defaultContentLanguage`, or Hugo's default of "en".
+    /// Pages of a plain directory of posts have no language, they are in this one.
+    pub fn default_language(&self) -> &str {
+        match self.config.as_ref() {
+            Some(cfg) => &cfg.default_language,
+            None => "en",
+        }
+    }
+
+    /// The top-level directory inside content
+    pub fn section(&self, page: &content::Page) -> String {
+        let mut parts = page.content_path.components();
+        match (parts.next(), parts.next()) {
+            (Some(first), Some(_)) => first.as_os_str().to_string_lossy().to_string(),
+            _ => String::new(),
+        }
+    }
+
+    /// How Hugo's `site.GetPage` refers to a page
+    pub fn page_ref(&self, page: &content::Page) -> String {
+        if self.config.is_none() {
+            // Relative to the directory we were given, the way it has always been
+            return content::page_ref(&page.rel_path);
+        }
+        // Hugo's logical path: from the content root, no extension, no language
+        let dir = page.content_path.parent().unwrap_or(path::Path::new(""));
+        let name = content_base_name(&page.content_path);
+        let logical = if content::is_bundle(&page.content_path) {
+            dir.to_path_buf()
+        } else {
+            dir.join(name)
+        };
+        format!("/{}", to_slashes(&logical))
+    }
+
+    /// The page's canonical URL. For a plain directory of posts we only know it
+    /// if the front matter sets it.
+    pub fn permalink(&self, page: &content::Page, fm: &FrontMatter) -> String {
+        let Some(cfg) = self.config.as_ref() else {
+            return fm.url.clone().unwrap_or_default();
+        };
+        if let Some(url) = fm.url.as_ref() {
+            return format!(
+                "{}/{}",
+                cfg.base_url.trim_end_matches('/'),
+                url.trim_start_matches('/')
+            );
+        }
+
+        let section = self.section(page);
+        let pattern = cfg
+            .languages
+            .iter()
+            .find(|l| l.code == page.lang)
+            .and_then(|l| l.permalinks.get(&section))
+            .or_else(|| cfg.permalinks.get(&section));
+        let mut path = match pattern {
+            Some(p) => expand_permalink(p, page, fm, &section),
+            None => {
+                let dir = page.content_path.parent().unwrap_or(path::Path::new(""));
+                let dir = if content::is_bundle(&page.content_path) {
+                    dir.parent().unwrap_or(path::Path::new(""))
+                } else {
+                    dir
+                };
+                let name = fm
+                    .slug
+                    .clone()
+                    .unwrap_or_else(|| content_base_name(&page.content_path));
+                format!("/{}/", to_slashes(&dir.join(name)))
+            }
+        };
+        if !cfg.disable_path_to_lower {
+            path = path.to_lowercase();
+        }
+        let is_default_lang = page.lang.is_empty() || page.lang == cfg.default_language;
+        if !is_default_lang || cfg.default_language_in_subdir {
+            let lang = if page.lang.is_empty() {
+                &cfg.default_language
+            } else {
+                &page.lang
+            };
+            path = format!("/{lang}{path}");
+        }
+        format!("{}{path}", cfg.base_url.trim_end_matches('/'))
+    }
+}
+
+impl Config {
+    fn from_value(v: &Value) -> anyhow::Result<Config> {
+        let default_language = get_str(v, "defaultContentLanguage").unwrap_or("en");
+        let mut languages = Vec::new();
+        if let Some(Value::Object(langs)) = get(v, "languages") {
+            for (code, l) in langs {
+                languages.push(Language {
+                    code: code.clone(),
+                    content_dir: get_str(l, "contentDir").map(path::PathBuf::from),
+                    permalinks: permalinks(get(l, "permalinks")),
+                });
+            }
+        }
+        Ok(Config {
+            base_url: get_str(v, "baseURL").unwrap_or_default().to_string(),
+            default_language: default_language.to_string(),
+            default_language_in_subdir: get(v, "defaultContentLanguageInSubdir")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+            disable_path_to_lower: get(v, "disablePathToLower")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+            permalinks: permalinks(get(v, "permalinks")),
+            content_dir: get_str(v, "contentDir")
+                .or_else(|| {
+                    // Hugo puts the default language's content dir on the language
+                    languages
+                        .iter()
+                        .find(|l| l.code == default_language)
+                        .and_then(|l| l.content_dir.as_deref())
+                        .and_then(|p| p.to_str())
+                })
+                .unwrap_or("content")
+                .into(),
+            languages,
+        })
+    }
+
+    // Multilingual sites can translate by file name: `my-post.fr.md`
+    fn language_from_filename(&self, p: &path::Path) -> String {
+        let name = p.file_name().unwrap_or_default().to_string_lossy();
+        let mut parts: Vec<&str> = name.split('.').collect();
+        parts.pop(); // extension
+        match parts.pop() {
+            Some(code) if !parts.is_empty() && self.languages.iter().any(|l| l.code == code) => {
+                code.to_string()
+            }
+            _ => self.default_language.clone(),
+        }
+    }
+}
+
+// Hugo has had two permalink layouts: `posts = "..."` and, since 0.112,
+// `[permalinks.page] posts = "..."`. We want the one for regular pages.
+fn permalinks(v: Option<&Value>) -> HashMap<String, String> {
+    let Some(v) = v else {
+        return HashMap::new();
+    };
+    let v = match get(v, "page") {
+        Some(page @ Value::Object(_)) => page,
+        _ => v,
+    };
+    let Value::Object(m) = v else {
+        return HashMap::new();
+    };
+    m.iter()
+        .filter_map(|(section, pattern)| Some((section.clone(), pattern.as_str()?.to_string())))
+        .collect()
+}
+
+// Replace Hugo's permalink tokens, e.g. "/:year/:month/:slug/"
+fn expand_permalink(
+    pattern: &str,
+    page: &content::Page,
+    fm: &FrontMatter,
+    section: &str,
+) -> String {
+    let date = crate::front_matter::parse_date(&fm.date);
+    let base_name = content_base_name(&page.content_path);
+    let title = urlize(&fm.title);
+    let slug = fm.slug.as_deref().map(urlize);
+    let sections = page
+        .content_path
+        .parent()
+        .map(|p| {
+            if content::is_bundle(&page.content_path) {
+                p.parent().unwrap_or(path::Path::new(""))
+            } else {
+                p
+            }
+        })
+        .map(to_slashes)
+        .unwrap_or_default();
+
+    let mut out = String::with_capacity(pattern.len());
+    let mut rest = pattern;
+    while let Some(pos) = rest.find(':') {
+        out.push_str(&rest[..pos]);
+        let token_len = rest[pos + 1..]
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .unwrap_or(rest.len() - pos - 1);
+        let token = &rest[pos + 1..pos + 1 + token_len];
+        let d = |fmt: &str| date.map(|d| d.format(fmt).to_string()).unwrap_or_default();
+        let value = match token {
+            "year" => d("%Y"),
+            "month" => d("%m"),
+            "monthname" => d("%B").to_lowercase(),
+            "day" => d("%d"),
+            "weekday" => d("%w"),
+            "weekdayname" => d("%A").to_lowercase(),
+            "yearday" => d("%j"),
+            "section" => section.to_string(),
+            "sections" => sections.clone(),
+            "title" => title.clone(),
+            "slug" => slug.clone().unwrap_or_else(|| title.clone()),
+            "slugorfilename" | "slugorcontentbasename" => {
+                slug.clone().unwrap_or_else(|| base_name.clone())
+            }
+            "filename" | "contentbasename" => base_name.clone(),
+            _ => format!(":{token}"),
+        };
+        out.push_str(&value);
+        rest = &rest[pos + 1 + token_len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+// The name Hugo uses for a page: the bundle directory or the file name
+// without extension and language.
+fn content_base_name(content_path: &path::Path) -> String {
+    let p = if content::is_bundle(content_path) {
+        content_path.parent().unwrap_or(content_path)
+    } else {
+        content_path
+    };
+    let name = p.file_name().unwrap_or_default().to_string_lossy();
+    if content::is_bundle(content_path) {
+        return name.to_string();
+    }
+    name.split('.').next().unwrap_or_default().to_string()
+}
+
+fn to_slashes(p: &path::Path) -> String {
+    p.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// Roughly Hugo's urlize: spaces become dashes, most punctuation goes
+fn urlize(s: &str) -> String {
+    s.trim()
+        .chars()
+        .filter_map(|c| match c {
+            ' ' | '\t' => Some('-'),
+            c if c.is_alphanumeric() || matches!(c, '-' | '_' | '.' | '/') => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+fn read_config(p: &path::Path) -> anyhow::Result<Value> {
+    let read = || -> anyhow::Result<Value> {
+        let contents = fs::read_to_string(p)?;
+        Ok(match p.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&contents)?,
+            Some("json") => serde_json::from_str(&contents)?,
+            _ => serde_yaml::from_str(&contents)?,
+        })
+    };
+    read().with_context(|| format!("{}", p.display()))
+}
+
+// Hugo lets a site split its config into a file per top level key, such as
+// `languages.toml` and `permalinks.toml`, or `permalinks.fr.toml` for one
+// language. Add those to `v`, without replacing anything it already has.
+fn merge_config_dir(
+    dir: &path::Path,
+    main: Option<&path::Path>,
+    v: &mut Value,
+) -> anyhow::Result<()> {
+    let mut files: Vec<path::PathBuf> = fs::read_dir(dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    files.sort();
+    for p in files {
+        let is_config = matches!(
+            p.extension().and_then(|e| e.to_str()),
+            Some("toml" | "yaml" | "yml" | "json")
+        );
+        if !is_config || Some(p.as_path()) == main {
+            continue;
+        }
+        let stem = p.file_stem().unwrap_or_default().to_string_lossy();
+        let file_value = read_config(&p)?;
+        let (name, lang) = match stem.split_once('.') {
+            Some((name, lang)) => (name, Some(lang)),
+            None => (stem.as_ref(), None),
+        };
+        let value = match (name, lang) {
+            ("hugo" | "config", None) => file_value,
+            (name, None) => serde_json::json!({ name: file_value }),
+            (name, Some(lang)) => {
+                serde_json::json!({ "languages": { lang: { name: file_value } } })
+            }
+        };
+        merge(v, value);
+    }
+    Ok(())
+}
+
+// Add what's in `from` to `into`, keeping the values `into` already has
+fn merge(into: &mut Value, from: Value) {
+    let (Value::Object(into), Value::Object(from)) = (into, from) else {
+        return;
+    };
+    for (key, value) in from {
+        match into.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(&key)) {
+            Some((_, existing)) => merge(existing, value),
+            None => {
+                into.insert(key, value);
+            }
+        }
+    }
+}
+
+// Hugo config keys are case insensitive
+fn get<'a>(v: &'a Value, key: &str) -> Option<&'a Value> {
+    v.as_object()?
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(key))
+        .map(|(_, v)| v)
+}
+
+fn get_str<'a>(v: &'a Value, key: &str) -> Option<&'a str> {
+    get(v, key)?.as_str()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site(config: Value) -> Site {
+        Site {
+            root: path::PathBuf::new(),
+            config: Some(Config::from_value(&config).unwrap()),
+        }
+    }
+
+    fn page(content_path: &str, lang: &str) -> content::Page {
+        content::Page {
+            path: content_path.into(),
+            rel_path: path::Path::new("content").join(content_path),
+            content_path: content_path.into(),
+            lang: lang.to_string(),
+        }
+    }
+
+    fn fm(yaml: &str) -> FrontMatter {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn permalink_patterns() {
+        let s = site(serde_json::json!({
+            "baseURL": "https://example.com/",
+            "permalinks": {
+                "posts": "/:year/:month/:slug/",
+                "notes": "/:sections/:slugorfilename/",
+            },
+        }));
+        let dated = fm("title: Bundle Post\ndate: 2023-07-04");
+        assert_eq!(
+            s.permalink(&page("posts/2023/my-bundle/index.md", "en"), &dated),
+            "https://example.com/2023/07/bundle-post/"
+        );
+        assert_eq!(
+            s.permalink(
+                &page("posts/first.md", "en"),
+                &fm("title: First\ndate: 2024-01-02T10:00:00Z\nslug: Erste Schritte")
+            ),
+            "https://example.com/2024/01/erste-schritte/"
+        );
+        assert_eq!(
+            s.permalink(&page("notes/rust/Tips.md", "en"), &dated),
+            "https://example.com/notes/rust/tips/"
+        );
+        // No pattern for this section
+        assert_eq!(
+            s.permalink(&page("about.md", "en"), &dated),
+            "https://example.com/about/"
+        );
+    }
+
+    #[test]
+    fn permalinks_page_table() {
+        let s = site(serde_json::json!({
+            "baseURL": "https://example.com",
+            "permalinks": {
+                "page": { "posts": "/:year/:filename/" },
+                "section": { "posts": "/archive/" },
+            },
+        }));
+        assert_eq!(
+            s.permalink(
+                &page("posts/first.md", "en"),
+                &fm("title: First\ndate: 2023-07-04")
+            ),
+            "https://example.com/2023/first/"
+        );
+    }
+
+    #[test]
+    fn bundle_and_single_file() {
+        let s = site(serde_json::json!({ "baseURL": "https://example.com/" }));
+        let f = fm("title: Trip\ndate: 2023-07-04");
+        let bundle = page("blog/trip/index.md", "en");
+        let single = page("blog/trip.md", "en");
+        assert_eq!(s.page_ref(&bundle), "/blog/trip");
+        assert_eq!(s.page_ref(&single), "/blog/trip");
+        assert_eq!(s.permalink(&bundle, &f), "https://example.com/blog/trip/");
+        assert_eq!(s.permalink(&single, &f), "https://example.com/blog/trip/");
+        assert_eq!(s.section(&bundle), "blog");
+        assert_eq!(s.section(&page("top.md", "en")), "");
+
+        let slugged = fm("title: Trip\ndate: 2023-07-04\nslug: Voyage");
+        assert_eq!(
+            s.permalink(&bundle, &slugged),
+            "https://example.com/blog/voyage/"
+        );
+        assert_eq!(
+            s.permalink(&single, &slugged),
+            "https://example.com/blog/voyage/"
+        );
+    }
+
+    #[test]
+    fn front_matter_url() {
+        for base in ["https://example.com", "https://example.com/"] {
+            let s = site(serde_json::json!({ "baseURL": base }));
+            for url in ["foo/", "/foo/"] {
+                let f = fm(&format!("title: T\ndate: 2023-07-04\nurl: {url}"));
+                assert_eq!(
+                    s.permalink(&page("blog/t.md", "en"), &f),
+                    "https://example.com/foo/",
+                    "{base} {url}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn language_prefix() {
+        let config = serde_json::json!({
+            "baseURL": "https://example.com/",
+            "defaultContentLanguage": "en",
+            "disablePathToLower": true,
+            "languages": {
+                "en": {},
+                "fr": { "permalinks": { "blog": "/journal/:filename/" } },
+            },
+        });
+        let f = fm("title: Un\ndate: 2023-07-04");
+        let s = site(config.clone());
+        let cfg = s.config.as_ref().unwrap();
+        assert_eq!(
+            cfg.language_from_filename(path::Path::new("blog/One.fr.md")),
+            "fr"
+        );
+        assert_eq!(
+            cfg.language_from_filename(path::Path::new("blog/v1.2.md")),
+            "en"
+        );
+        assert_eq!(
+            s.permalink(&page("blog/One.md", "en"), &f),
+            "https://example.com/blog/One/"
+        );
+        assert_eq!(
+            s.permalink(&page("blog/One.fr.md", "fr"), &f),
+            "https://example.com/fr/journal/One/"
+        );
+        assert_eq!(
+            s.permalink(&page("blog/One.md", ""), &f),
+            "https://example.com/blog/One/"
+        );
+
+        let mut config = config;
+        config["defaultContentLanguageInSubdir"] = true.into();
+        let s = site(config);
+        assert_eq!(
+            s.permalink(&page("blog/One.md", "en"), &f),
+            "https://example.com/en/blog/One/"
+        );
+        assert_eq!(
+            s.permalink(&page("blog/One.md", ""), &f),
+            "https://example.com/en/blog/One/"
+        );
+    }
+
+    #[test]
+    fn default_language() {
+        let plain = Site {
+            root: path::PathBuf::new(),
+            config: None,
+        };
+        assert_eq!(plain.default_language(), "en");
+        let fr = site(serde_json::json!({ "defaultContentLanguage": "fr" }));
+        assert_eq!(fr.default_language(), "fr");
+    }
+
+    #[test]
+    fn urlize_titles() {
+        assert_eq!(urlize("Hello, World!"), "Hello-World");
+        assert_eq!(urlize("  C++ in 2024 "), "C-in-2024");
+        assert_eq!(urlize("a/b.c_d-e"), "a/b.c_d-e");
+        assert_eq!(urlize("Crème brûlée\tà Zürich"), "Crème-brûlée-à-Zürich");
+        assert_eq!(urlize("日本語 テスト"), "日本語-テスト");
+    }
+
+    #[test]
+    fn nested_language_content_dir() {
+        let root = std::env::temp_dir().join(format!("hugo-ai-site-{}", std::process::id()));
+        let write = |rel: &str, contents: &str| {
+            let p = root.join(rel);
+            fs::create_dir_all(p.parent().unwrap()).unwrap();
+            fs::write(p, contents).unwrap();
+        };
+        write(
+            "hugo.toml",
+            "defaultContentLanguage = 'en'\n\
+             [languages.en]\ncontentDir = 'content'\n\
+             [languages.fr]\ncontentDir = 'content/fr'\n",
+        );
+        write("content/blog/one.md", "");
+        write("content/fr/blog/un.md", "");
+        let s = Site::load(root.to_str().unwrap()).unwrap();
+        let pages = s.pages(&content::Filter::default());
+        fs::remove_dir_all(&root).unwrap();
+
+        let mut found: Vec<(String, String, String)> = pages
+            .unwrap()
+            .into_iter()
+            .map(|p| (to_slashes(&p.rel_path), to_slashes(&p.content_path), p.lang))
+            .collect();
+        found.sort();
+        let expected = [
+            ("content/blog/one.md", "blog/one.md", "en"),
+            ("content/fr/blog/un.md", "blog/un.md", "fr"),
+        ]
+        .map(|(a, b, c)| (a.to_string(), b.to_string(), c.to_string()));
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn split_config_dir() {
+        let root = std::env::temp_dir().join(format!("hugo-ai-split-{}", std::process::id()));
+        let write = |rel: &str, contents: &str| {
+            let p = root.join(rel);
+            fs::create_dir_all(p.parent().unwrap()).unwrap();
+            fs::write(p, contents).unwrap();
+        };
+        write(
+            "config/_default/hugo.toml",
+            "baseURL = 'https://example.com/'
+",
+        );
+        write(
+            "config/_default/languages.toml",
+            "[en]
+weight = 1
+[fr]
+contentDir = 'content-fr'
+",
+        );
+        write(
+            "config/_default/permalinks.toml",
+            "posts = '/:year/:slug/'
+",
+        );
+        write(
+            "config/_default/permalinks.fr.yaml",
+            "posts: /articles/:slug/
+",
+        );
+        write("content/posts/one.md", "");
+        write("content-fr/posts/un.md", "");
+        let s = Site::load(root.to_str().unwrap()).unwrap();
+        let pages = s.pages(&content::Filter::default());
+        fs::remove_dir_all(&root).unwrap();
+
+        let mut pages = pages.unwrap();
+        pages.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+        let langs: Vec<&str> = pages.iter().map(|p| p.lang.as_str()).collect();
+        assert_eq!(langs, ["en", "fr"]);
+        let f = fm("title: One\ndate: 2023-07-04");
+        assert_eq!(s.permalink(&pages[0], &f), "https://example.com/2023/one/");
+        assert_eq!(
+            s.permalink(&pages[1], &f),
+            "https://example.com/fr/articles/one/"
+        );
+    }
+}
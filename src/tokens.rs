@@ -0,0 +1,100 @@
+// MIT License
+// Copyright (c) 2024 Graham King
+
+use std::sync::OnceLock;
+
+use tiktoken_rs::CoreBPE;
+
+// Room left in the context window for the model's answer
+const ANSWER_TOKENS: usize = 4_096;
+
+// OpenAI's tokenizer for text-embedding-3 and gpt-4. Other models split text a bit
+// differently, but it's close enough to size things by.
+fn cl100k() -> &'static CoreBPE {
+    static BPE: OnceLock<CoreBPE> = OnceLock::new();
+    // The vocabulary is compiled in, so this can't fail at runtime
+    BPE.get_or_init(|| tiktoken_rs::cl100k_base().expect("cl100k_base vocabulary"))
+}
+
+// gpt-4o's tokenizer
+fn o200k() -> &'static CoreBPE {
+    static BPE: OnceLock<CoreBPE> = OnceLock::new();
+    BPE.get_or_init(|| tiktoken_rs::o200k_base().expect("o200k_base vocabulary"))
+}
+
+/// How many tokens `text` is
+pub fn count(text: &str) -> usize {
+    cl100k().encode_ordinary(text).len()
+}
+
+/// How many tokens `text` is to chat model `model`. Anthropic don't publish
+/// Claude's tokenizer. It makes about a fifth more tokens than cl100k, so we
+/// add that on to stay under the limit.
+pub fn count_for(model: &str, text: &str) -> usize {
+    if model.starts_with("gpt-4o") {
+        o200k().encode_ordinary(text).len()
+    } else if model.starts_with("claude") {
+        count(text) * 6 / 5
+    } else {
+        count(text)
+    }
+}
+
+/// Most tokens we can send to `model`, prompts included
+pub fn input_limit(model: &str) -> usize {
+    let context_window = if model.starts_with("gpt-4o") {
+        128_000
+    } else if model.starts_with("claude-3") {
+        200_000
+    } else {
+        8_192
+    };
+    context_window - ANSWER_TOKENS
+}
+
+/// The start of `text` that is at most `max` tokens to `model`, ending
+/// between words
+pub fn truncate<'a>(model: &str, text: &'a str, max: usize) -> &'a str {
+    if count_for(model, text) <= max {
+        return text;
+    }
+    let ends: Vec<usize> = text
+        .char_indices()
+        .map(|(idx, _)| idx)
+        .chain([text.len()])
+        .collect();
+    // The empty start always fits, so this is at least 1
+    let fits = ends.partition_point(|end| count_for(model, &text[..*end]) <= max);
+    let start = &text[..ends[fits - 1]];
+    match start.rfind(char::is_whitespace) {
+        Some(idx) if idx > 0 => start[..idx].trim_end(),
+        // One giant word
+        _ => start,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncate_fits_and_ends_between_words() {
+        let text = "Ça coûte très cher, œuvre naïve. ".repeat(50);
+        for model in ["gpt-4o-mini", "claude-3-haiku-20240307"] {
+            let start = truncate(model, &text, 40);
+            assert!(count_for(model, start) <= 40, "{model}: {start}");
+            assert!(text.starts_with(start));
+            assert!(text[start.len()..].starts_with(char::is_whitespace));
+        }
+        assert_eq!(truncate("gpt-4o", "court", 40), "court");
+    }
+
+    #[test]
+    fn truncate_without_spaces() {
+        let text = "漢字だけの長い文章".repeat(30);
+        let start = truncate("gpt-4o", &text, 20);
+        assert!(!start.is_empty());
+        assert!(count_for("gpt-4o", start) <= 20);
+        assert!(text.starts_with(start));
+    }
+}